@@ -0,0 +1,133 @@
+//! syscall count and wall time of QueryPathInfo round trips with and without
+//! buffered transports
+//!
+//! run with `cargo bench --bench transport`
+
+use serde::{Deserialize, Serialize};
+use sirius::client::Client;
+use sirius::de::Deserializer;
+use sirius::protocol::*;
+use sirius::ser::Serializer;
+use sirius::types::UnkeyedValidPathInfo;
+use std::cell::Cell;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::rc::Rc;
+
+const ROUNDS: usize = 10_000;
+
+/// counts calls into the underlying stream, each one is a syscall
+struct Counting {
+    stream: UnixStream,
+    calls: Rc<Cell<usize>>,
+}
+
+impl Read for Counting {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.calls.set(self.calls.get() + 1);
+        self.stream.read(buf)
+    }
+}
+
+impl Write for Counting {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.calls.set(self.calls.get() + 1);
+        self.stream.write(buf)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+fn daemon(stream: UnixStream) {
+    let mut read = BufReader::new(stream.try_clone().unwrap());
+    let mut write = BufWriter::new(stream);
+    let info = UnkeyedValidPathInfo {
+        deriver: Some(
+            "/nix/store/0c7c1r3yfqb2qpr8gq0mwz4zcg3dk2ms-hello-2.12.drv"
+                .parse()
+                .unwrap(),
+        ),
+        hash: "sha256:1b2f2vlzl4mk5vqbl3sbqmwlbk0cm5ixrmx3bdg6c5xrh4n5wbxl"
+            .parse()
+            .unwrap(),
+        references: vec!["/nix/store/9lkz5r8b5srq6z3x5nzi1k8d5mgr4z16-glibc-2.35"
+            .parse()
+            .unwrap()],
+        registration_time: 0,
+        nar_size: 42,
+        ultimate: false,
+        sigs: vec![],
+        ca: None,
+    };
+    {
+        let mut des = Deserializer::new(&mut read);
+        u64::deserialize(&mut des).unwrap();
+        let mut ser = Serializer::new(&mut write);
+        WORKER_MAGIC_2.serialize(&mut ser).unwrap();
+        PROTOCOL_VERSION.serialize(&mut ser).unwrap();
+        write.flush().unwrap();
+        let mut des = Deserializer::new(&mut read);
+        u64::deserialize(&mut des).unwrap();
+        Option::<u64>::deserialize(&mut des).unwrap();
+        bool::deserialize(&mut des).unwrap();
+        let mut ser = Serializer::new(&mut write);
+        "bench".serialize(&mut ser).unwrap();
+        0_u64.serialize(&mut ser).unwrap();
+        STDERR_LAST.serialize(&mut ser).unwrap();
+        write.flush().unwrap();
+    }
+    loop {
+        let mut des = Deserializer::new(&mut read);
+        if u64::deserialize(&mut des).is_err() {
+            return;
+        }
+        String::deserialize(&mut des).unwrap();
+        let mut ser = Serializer::new(&mut write);
+        STDERR_LAST.serialize(&mut ser).unwrap();
+        ser.encode(&Some(&info)).unwrap();
+        write.flush().unwrap();
+    }
+}
+
+fn run<W: Write, R: Read>(name: &str, wrap: impl FnOnce(Counting, Counting) -> Client<W, R>) {
+    let (ours, theirs) = UnixStream::pair().unwrap();
+    let peer = std::thread::spawn(move || daemon(theirs));
+    let calls = Rc::new(Cell::new(0));
+    let start = std::time::Instant::now();
+    {
+        let mut client = wrap(
+            Counting {
+                stream: ours.try_clone().unwrap(),
+                calls: calls.clone(),
+            },
+            Counting {
+                stream: ours.try_clone().unwrap(),
+                calls: calls.clone(),
+            },
+        );
+        let path = "/nix/store/7rjj86a15146cq1d6fgrmfbvb7gwz5f1-hello-2.12"
+            .parse()
+            .unwrap();
+        for _ in 0..ROUNDS {
+            client.query_path_info(&path).unwrap();
+        }
+    }
+    let elapsed = start.elapsed();
+    ours.shutdown(std::net::Shutdown::Both).unwrap();
+    peer.join().unwrap();
+    println!(
+        "{:<12} {:>8} syscalls {:>8.2} per round trip {:>10.2?}",
+        name,
+        calls.get(),
+        calls.get() as f64 / ROUNDS as f64,
+        elapsed
+    );
+}
+
+fn main() {
+    run("unbuffered", |w, r| Client::new(w, r).unwrap());
+    run("buffered", |w, r| {
+        Client::new(BufWriter::new(w), BufReader::new(r)).unwrap()
+    });
+}
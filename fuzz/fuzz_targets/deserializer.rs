@@ -0,0 +1,55 @@
+//! decode every protocol type from untrusted bytes
+//!
+//! the first byte picks the type, the second the protocol minor version
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use serde::de::DeserializeOwned;
+use sirius::consts::BuildStatus;
+use sirius::de::{Deserializer, Limits, WorkerDecode};
+use sirius::protocol::*;
+use sirius::types::*;
+
+fn serde<T: DeserializeOwned>(data: &[u8], version: u64) {
+    let _ = T::deserialize(
+        &mut Deserializer::from_slice_with_version(data, version).with_limits(Limits::SERVER),
+    );
+    let mut read = data;
+    let _ = T::deserialize(
+        &mut Deserializer::with_version(&mut read, version).with_limits(Limits::SERVER),
+    );
+}
+
+fn worker<T: WorkerDecode>(data: &[u8], version: u64) {
+    let _ = Deserializer::from_slice_with_version(data, version)
+        .with_limits(Limits::SERVER)
+        .decode::<T>();
+    let mut read = data;
+    let _ = Deserializer::with_version(&mut read, version)
+        .with_limits(Limits::SERVER)
+        .decode::<T>();
+}
+
+fuzz_target!(|data: &[u8]| {
+    let (ty, minor, data) = match data {
+        [ty, minor, data @ ..] => (*ty, *minor as u64, data),
+        _ => return,
+    };
+    let version = protocol_version_major(PROTOCOL_VERSION) | minor;
+    match ty {
+        0 => worker::<UnkeyedValidPathInfo>(data, version),
+        1 => worker::<ValidPathInfo>(data, version),
+        2 => worker::<Option<UnkeyedValidPathInfo>>(data, version),
+        3 => worker::<StorePath>(data, version),
+        4 => worker::<Realisation>(data, version),
+        5 => worker::<DrvOutputs>(data, version),
+        6 => serde::<BasicDerivation>(data, version),
+        7 => serde::<ClientSettings>(data, version),
+        8 => serde::<Op>(data, version),
+        9 => serde::<BuildStatus>(data, version),
+        10 => serde::<Vec<String>>(data, version),
+        11 => worker::<Vec<DerivedPath>>(data, version),
+        12 => worker::<BuildResult>(data, version),
+        _ => (),
+    }
+});
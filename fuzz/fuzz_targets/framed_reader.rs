@@ -0,0 +1,28 @@
+//! read a framed stream to the end, and decode path infos from one the way
+//! AddMultipleToStore does
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use sirius::de::{Deserializer, FramedReader, Limits};
+use sirius::protocol::PROTOCOL_VERSION;
+use sirius::types::ValidPathInfo;
+use std::io::Read;
+
+fuzz_target!(|data: &[u8]| {
+    let mut read = data;
+    let _ = FramedReader::new(&mut read).read_to_end(&mut vec![]);
+
+    let mut read = data;
+    let mut fr = FramedReader::new(&mut read);
+    let _ = (|| {
+        let num_paths = Deserializer::with_version(&mut fr, PROTOCOL_VERSION)
+            .with_limits(Limits::SERVER)
+            .decode::<u64>()?;
+        for _ in 0..num_paths {
+            Deserializer::with_version(&mut fr, PROTOCOL_VERSION)
+                .with_limits(Limits::SERVER)
+                .decode::<ValidPathInfo>()?;
+        }
+        Ok::<_, sirius::error::Error>(())
+    })();
+});
@@ -0,0 +1,20 @@
+//! run a whole client session against the server from an in-memory stream
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use sirius::server::{handle, Db};
+
+fuzz_target!(|data: &[u8]| {
+    // nars are unpacked below this, builds fail since there is no bwrap. a
+    // fresh store per input keeps runs independent so crashes reproduce
+    let store = tempdir::TempDir::new("sirius-fuzz-store").unwrap();
+    let _ = handle(
+        data,
+        std::io::sink(),
+        store.path(),
+        &Db::default(),
+        "/nonexistent/bwrap",
+        "/nonexistent/sh",
+        None,
+    );
+});
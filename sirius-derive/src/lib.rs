@@ -0,0 +1,359 @@
+//! derive macros for the nix worker protocol encodings in `sirius`
+//!
+//! fields are encoded in declaration order through `WorkerEncode`/`WorkerDecode`,
+//! the following field attributes adjust that:
+//!
+//! - `#[worker(empty_as_none)]`: `Option<T>` sent as a string, `None` being empty
+//! - `#[worker(store_path)]`: `String` holding a base name, sent with the store dir prefix
+//! - `#[worker(since = N)]`: only present on the wire from protocol minor version `N`
+//! - `#[worker(skip)]`: not on the wire at all, decoded as `Default::default()`
+//! - `#[worker(serde)]`: encoded through the serde implementation of the field
+//!
+//! decode errors name the field they happened in, like `Type.field[3]`
+//!
+//! fieldless enums are sent as their u64 discriminant
+//!
+//! when `sirius` is built with its `async` feature, `WorkerDecode` also
+//! derives `AsyncWorkerDecode`, unless a field is decoded through serde
+
+use proc_macro2::TokenStream;
+use quote::{format_ident, quote};
+use syn::spanned::Spanned;
+use syn::{parse_macro_input, Data, DeriveInput, Fields, Lit, Meta, NestedMeta};
+
+#[derive(Default)]
+struct FieldAttrs {
+    empty_as_none: bool,
+    store_path: bool,
+    since: Option<u64>,
+    skip: bool,
+    serde: bool,
+}
+
+fn parse_field_attrs(attrs: &[syn::Attribute]) -> syn::Result<FieldAttrs> {
+    let mut res = FieldAttrs::default();
+    for attr in attrs.iter().filter(|a| a.path.is_ident("worker")) {
+        let list = match attr.parse_meta()? {
+            Meta::List(list) => list,
+            meta => return Err(syn::Error::new(meta.span(), "expected #[worker(...)]")),
+        };
+        for nested in list.nested {
+            match nested {
+                NestedMeta::Meta(Meta::Path(p)) if p.is_ident("empty_as_none") => {
+                    res.empty_as_none = true
+                }
+                NestedMeta::Meta(Meta::Path(p)) if p.is_ident("store_path") => {
+                    res.store_path = true
+                }
+                NestedMeta::Meta(Meta::Path(p)) if p.is_ident("skip") => res.skip = true,
+                NestedMeta::Meta(Meta::Path(p)) if p.is_ident("serde") => res.serde = true,
+                NestedMeta::Meta(Meta::NameValue(nv)) if nv.path.is_ident("since") => {
+                    match &nv.lit {
+                        Lit::Int(i) => res.since = Some(i.base10_parse()?),
+                        lit => return Err(syn::Error::new(lit.span(), "expected minor version")),
+                    }
+                }
+                other => return Err(syn::Error::new(other.span(), "unknown worker attribute")),
+            }
+        }
+    }
+    let kinds = [res.empty_as_none, res.store_path, res.skip, res.serde];
+    if kinds.iter().filter(|x| **x).count() > 1 {
+        return Err(syn::Error::new(
+            attrs[0].span(),
+            "empty_as_none, store_path, skip and serde are mutually exclusive",
+        ));
+    }
+    Ok(res)
+}
+
+struct Field {
+    member: syn::Member,
+    binding: syn::Ident,
+    ty: syn::Type,
+    attrs: FieldAttrs,
+}
+
+fn fields(fields: &Fields) -> syn::Result<Vec<Field>> {
+    fields
+        .iter()
+        .enumerate()
+        .map(|(i, f)| {
+            Ok(Field {
+                member: match &f.ident {
+                    Some(ident) => syn::Member::Named(ident.clone()),
+                    None => syn::Member::Unnamed(i.into()),
+                },
+                binding: format_ident!("__field{}", i),
+                ty: f.ty.clone(),
+                attrs: parse_field_attrs(&f.attrs)?,
+            })
+        })
+        .collect()
+}
+
+fn enum_tags(data: &syn::DataEnum) -> syn::Result<Vec<(syn::Ident, TokenStream)>> {
+    let mut next = quote!(0_u64);
+    data.variants
+        .iter()
+        .map(|v| {
+            if !matches!(v.fields, Fields::Unit) {
+                return Err(syn::Error::new(
+                    v.span(),
+                    "only fieldless enums can be derived",
+                ));
+            }
+            let tag = match &v.discriminant {
+                Some((_, expr)) => quote!((#expr) as u64),
+                None => next.clone(),
+            };
+            next = quote!(#tag + 1);
+            Ok((v.ident.clone(), tag))
+        })
+        .collect()
+}
+
+#[proc_macro_derive(WorkerEncode, attributes(worker))]
+pub fn derive_worker_encode(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_encode(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+fn expand_encode(input: DeriveInput) -> syn::Result<TokenStream> {
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let body = match &input.data {
+        Data::Struct(data) => {
+            let stmts = fields(&data.fields)?
+                .into_iter()
+                .filter(|f| !f.attrs.skip)
+                .map(|f| {
+                    let member = &f.member;
+                    let encode = if f.attrs.empty_as_none {
+                        quote!(::sirius::ser::encode_empty_as_none(&self.#member, __ser)?;)
+                    } else if f.attrs.store_path {
+                        quote!(::sirius::ser::encode_store_path(&self.#member, __ser)?;)
+                    } else if f.attrs.serde {
+                        quote!(::sirius::ser::encode_serde(&self.#member, __ser)?;)
+                    } else {
+                        quote!(::sirius::ser::WorkerEncode::encode(&self.#member, __ser)?;)
+                    };
+                    match f.attrs.since {
+                        Some(minor) => quote! {
+                            if ::sirius::protocol::protocol_version_minor(__ser.version()) >= #minor {
+                                #encode
+                            }
+                        },
+                        None => encode,
+                    }
+                });
+            quote!(#(#stmts)* Ok(()))
+        }
+        Data::Enum(data) => {
+            let arms = enum_tags(data)?
+                .into_iter()
+                .map(|(ident, tag)| quote!(#name::#ident => #tag,));
+            quote! {
+                let tag: u64 = match self { #(#arms)* };
+                ::sirius::ser::WorkerEncode::encode(&tag, __ser)
+            }
+        }
+        Data::Union(_) => return Err(syn::Error::new(input.span(), "unions are not supported")),
+    };
+    Ok(quote! {
+        impl #impl_generics ::sirius::ser::WorkerEncode for #name #ty_generics #where_clause {
+            fn encode<__W: ::std::io::Write>(
+                &self,
+                __ser: &mut ::sirius::ser::Serializer<__W>,
+            ) -> ::sirius::error::Result<()> {
+                #body
+            }
+        }
+    })
+}
+
+#[proc_macro_derive(WorkerDecode, attributes(worker))]
+pub fn derive_worker_decode(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_decode(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+fn expand_decode(input: DeriveInput) -> syn::Result<TokenStream> {
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let body = match &input.data {
+        Data::Struct(data) => {
+            let fields = fields(&data.fields)?;
+            let stmts = fields.iter().map(|f| {
+                let binding = &f.binding;
+                let segment = match &f.member {
+                    syn::Member::Named(ident) => format!(".{}", ident),
+                    syn::Member::Unnamed(index) => format!(".{}", index.index),
+                };
+                let decode = if f.attrs.empty_as_none {
+                    quote!(::sirius::de::decode_empty_as_none(__de))
+                } else if f.attrs.store_path {
+                    quote!(::sirius::de::decode_store_path(__de))
+                } else if f.attrs.serde {
+                    quote!(::sirius::de::decode_serde(__de))
+                } else {
+                    quote!(::sirius::de::WorkerDecode::decode(__de))
+                };
+                let decode = quote!(#decode.map_err(|e| __de.context(e, #segment))?);
+                if f.attrs.skip {
+                    return quote!(let #binding = ::std::default::Default::default(););
+                }
+                match f.attrs.since {
+                    Some(minor) => quote! {
+                        let #binding = if ::sirius::protocol::protocol_version_minor(
+                            __de.version(),
+                        ) >= #minor {
+                            #decode
+                        } else {
+                            ::std::default::Default::default()
+                        };
+                    },
+                    None => quote!(let #binding = #decode;),
+                }
+            });
+            let inits = fields.iter().map(|f| {
+                let member = &f.member;
+                let binding = &f.binding;
+                quote!(#member: #binding)
+            });
+            let name_str = name.to_string();
+            quote! {
+                ::sirius::de::decode_struct(__de, #name_str, |__de| {
+                    #(#stmts)*
+                    Ok(#name { #(#inits),* })
+                })
+            }
+        }
+        Data::Enum(data) => {
+            let arms = enum_tags(data)?
+                .into_iter()
+                .map(|(ident, tag)| quote!(tag if tag == #tag => Ok(#name::#ident),));
+            let name_str = name.to_string();
+            quote! {
+                let tag: u64 = ::sirius::de::WorkerDecode::decode(__de)?;
+                match tag {
+                    #(#arms)*
+                    tag => Err(::sirius::error::Error::Message(
+                        format!("invalid {} tag {}", #name_str, tag),
+                    )),
+                }
+            }
+        }
+        Data::Union(_) => return Err(syn::Error::new(input.span(), "unions are not supported")),
+    };
+    let async_impl = expand_decode_async(&input)?.map(|body| {
+        quote! {
+            ::sirius::__if_async! {
+                impl #impl_generics ::sirius::de::AsyncWorkerDecode for #name #ty_generics
+                #where_clause
+                {
+                    async fn decode_async<__R: ::sirius::de::AsyncRead + Unpin + Send>(
+                        __de: &mut ::sirius::de::AsyncDeserializer<'_, __R>,
+                    ) -> ::sirius::error::Result<Self> {
+                        #body
+                    }
+                }
+            }
+        }
+    });
+    Ok(quote! {
+        impl #impl_generics ::sirius::de::WorkerDecode for #name #ty_generics #where_clause {
+            fn decode<__R: ::std::io::Read>(
+                __de: &mut ::sirius::de::Deserializer<__R>,
+            ) -> ::sirius::error::Result<Self> {
+                #body
+            }
+        }
+        #async_impl
+    })
+}
+
+/// the body of `AsyncWorkerDecode::decode_async`, `None` when a field is
+/// decoded through serde, which can not be read incrementally
+fn expand_decode_async(input: &DeriveInput) -> syn::Result<Option<TokenStream>> {
+    let name = &input.ident;
+    let name_str = name.to_string();
+    let body = match &input.data {
+        Data::Struct(data) => {
+            let fields = fields(&data.fields)?;
+            if fields.iter().any(|f| f.attrs.serde) {
+                return Ok(None);
+            }
+            let stmts = fields.iter().map(|f| {
+                let binding = &f.binding;
+                let segment = match &f.member {
+                    syn::Member::Named(ident) => format!(".{}", ident),
+                    syn::Member::Unnamed(index) => format!(".{}", index.index),
+                };
+                let decode = if f.attrs.empty_as_none {
+                    quote!(::sirius::de::decode_empty_as_none_async(__de))
+                } else if f.attrs.store_path {
+                    quote!(::sirius::de::decode_store_path_async(__de))
+                } else {
+                    let ty = &f.ty;
+                    quote!(<#ty as ::sirius::de::AsyncWorkerDecode>::decode_async(__de))
+                };
+                let decode = quote! {
+                    match #decode.await {
+                        Ok(x) => x,
+                        Err(e) => return Err(__de.context(e, #segment)),
+                    }
+                };
+                if f.attrs.skip {
+                    return quote!(let #binding = ::std::default::Default::default(););
+                }
+                match f.attrs.since {
+                    Some(minor) => quote! {
+                        let #binding = if ::sirius::protocol::protocol_version_minor(
+                            __de.version(),
+                        ) >= #minor {
+                            #decode
+                        } else {
+                            ::std::default::Default::default()
+                        };
+                    },
+                    None => quote!(let #binding = #decode;),
+                }
+            });
+            let inits = fields.iter().map(|f| {
+                let member = &f.member;
+                let binding = &f.binding;
+                quote!(#member: #binding)
+            });
+            quote! {
+                __de.enter_struct();
+                let __res = async {
+                    #(#stmts)*
+                    Ok(#name { #(#inits),* })
+                }
+                .await;
+                __de.leave_struct(#name_str, __res)
+            }
+        }
+        Data::Enum(data) => {
+            let arms = enum_tags(data)?
+                .into_iter()
+                .map(|(ident, tag)| quote!(tag if tag == #tag => Ok(#name::#ident),));
+            quote! {
+                let tag = __de.read_u64().await?;
+                match tag {
+                    #(#arms)*
+                    tag => Err(::sirius::error::Error::Message(
+                        format!("invalid {} tag {}", #name_str, tag),
+                    )),
+                }
+            }
+        }
+        Data::Union(_) => return Err(syn::Error::new(input.span(), "unions are not supported")),
+    };
+    Ok(Some(body))
+}
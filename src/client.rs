@@ -1,8 +1,13 @@
-use crate::de::Deserializer;
+use crate::consts::BuildMode;
+use crate::de::{Deserializer, WorkerDecode};
 use crate::protocol::*;
-use crate::ser::Serializer;
-use crate::types::ValidPathInfo;
+use crate::ser::{Serializer, WorkerEncode};
+use crate::signing::PublicKey;
+use crate::types::{
+    BasicDerivation, BuildResult, DerivedPath, StorePath, UnkeyedValidPathInfo, ValidPathInfo,
+};
 use serde::{Deserialize, Serialize};
+use std::io::{BufReader, BufWriter};
 use std::os::unix::net::UnixStream;
 use thiserror::Error;
 
@@ -21,18 +26,29 @@ pub enum ClientError {
 pub struct Client<W, R> {
     w: W,
     r: R,
+    version: u64,
+    daemon_version: Option<String>,
 }
 
-pub fn daemon() -> Result<Client<UnixStream, UnixStream>> {
+pub fn daemon() -> Result<Client<BufWriter<UnixStream>, BufReader<UnixStream>>> {
     let stream = UnixStream::connect("/nix/var/nix/daemon-socket/socket")?;
-    Client::new(stream.try_clone()?, stream.try_clone()?)
+    Client::new(
+        BufWriter::new(stream.try_clone()?),
+        BufReader::new(stream.try_clone()?),
+    )
 }
 
 impl<W: std::io::Write, R: std::io::Read> Client<W, R> {
     pub fn new(w: W, r: R) -> Result<Self> {
-        let mut client = Self { w, r };
+        let mut client = Self {
+            w,
+            r,
+            version: PROTOCOL_VERSION,
+            daemon_version: None,
+        };
 
         client.write(WORKER_MAGIC_1)?;
+        client.flush()?;
 
         let magic: u64 = client.read()?;
         if magic != WORKER_MAGIC_2 {
@@ -43,27 +59,57 @@ impl<W: std::io::Write, R: std::io::Read> Client<W, R> {
         if protocol_version_major(version) != protocol_version_major(PROTOCOL_VERSION) {
             panic!("protocol major version mismatch")
         }
-        if protocol_version_minor(version) < 33 {
+        if protocol_version_minor(version) < protocol_version_minor(MIN_PROTOCOL_VERSION) {
             panic!("protocol minor version too low")
         }
 
         client.write(PROTOCOL_VERSION)?;
+        client.version = std::cmp::min(version, PROTOCOL_VERSION);
         client.write(0u64)?; // obsolete CPU affinity
         client.write(false)?; // obsolete reserve space
+        client.flush()?;
 
-        let version: String = client.read()?;
-        println!("daemon version string: {}", version);
+        if protocol_version_minor(client.version) >= 33 {
+            client.daemon_version = Some(client.read()?);
+        }
+        if protocol_version_minor(client.version) >= 35 {
+            client.read::<u64>()?; // trusted flag
+        }
         client.process_stderr()?;
         Ok(client)
     }
+    /// protocol version negotiated with the daemon
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+    /// what the daemon says it is, like `nix (Nix) 2.18.1`, sent since 1.33
+    pub fn daemon_version(&self) -> Option<&str> {
+        self.daemon_version.as_deref()
+    }
     pub fn write<T: Serialize>(&mut self, value: T) -> Result<()> {
-        value.serialize(&mut Serializer::new(&mut self.w))?;
+        value.serialize(&mut Serializer::with_version(&mut self.w, self.version))?;
         Ok(())
     }
     pub fn read<'a, T: Deserialize<'a>>(&'a mut self) -> Result<T> {
-        Ok(T::deserialize(&mut Deserializer::new(&mut self.r))?)
+        Ok(T::deserialize(&mut Deserializer::with_version(
+            &mut self.r,
+            self.version,
+        ))?)
+    }
+    pub fn encode<T: WorkerEncode + ?Sized>(&mut self, value: &T) -> Result<()> {
+        Serializer::with_version(&mut self.w, self.version).encode(value)?;
+        Ok(())
     }
+    pub fn decode<T: WorkerDecode>(&mut self) -> Result<T> {
+        Ok(Deserializer::with_version(&mut self.r, self.version).decode()?)
+    }
+    /// send everything written so far, the writer may be buffered
+    pub fn flush(&mut self) -> Result<()> {
+        Ok(self.w.flush()?)
+    }
+    /// ends our turn and handles daemon messages until STDERR_LAST
     pub fn process_stderr(&mut self) -> Result<()> {
+        self.flush()?;
         loop {
             let msg: u64 = self.read()?;
             match msg {
@@ -82,13 +128,13 @@ impl<W: std::io::Write, R: std::io::Read> Client<W, R> {
             }
         }
     }
-    pub fn is_valid_path(&mut self, path: &str) -> Result<bool> {
+    pub fn is_valid_path(&mut self, path: &StorePath) -> Result<bool> {
         self.write(Op::IsValidPath)?;
         self.write(path)?;
         self.process_stderr()?;
         self.read::<bool>()
     }
-    pub fn query_path_from_hash_part(&mut self, hash: &str) -> Result<String> {
+    pub fn query_path_from_hash_part(&mut self, hash: &str) -> Result<StorePath> {
         self.write(Op::QueryPathFromHashPart)?;
         self.write(hash)?;
         self.process_stderr()?;
@@ -96,10 +142,10 @@ impl<W: std::io::Write, R: std::io::Read> Client<W, R> {
         if path.is_empty() {
             Err(ClientError::Generic(String::from("invalid path")))
         } else {
-            Ok(path)
+            Ok(path.parse()?)
         }
     }
-    pub fn nar_from_path(&mut self, path: &str) -> Result<()> {
+    pub fn nar_from_path(&mut self, path: &StorePath) -> Result<()> {
         self.write(Op::NarFromPath)?;
         self.write(path)?;
         self.process_stderr()?;
@@ -111,48 +157,89 @@ impl<W: std::io::Write, R: std::io::Read> Client<W, R> {
         self.process_stderr()?;
         self.read()
     }
-    pub fn query_path_info(&mut self, path: &str) -> Result<ValidPathInfo> {
+    pub fn build_paths(&mut self, paths: &[DerivedPath], mode: BuildMode) -> Result<()> {
+        self.write(Op::BuildPaths)?;
+        self.encode(paths)?;
+        if protocol_version_minor(self.version) >= 15 {
+            self.write(mode)?;
+        }
+        self.process_stderr()?;
+        self.read::<u64>()?;
+        Ok(())
+    }
+    pub fn build_derivation(
+        &mut self,
+        drv: &BasicDerivation,
+        mode: BuildMode,
+    ) -> Result<BuildResult> {
+        self.write(Op::BuildDerivation)?;
+        self.write(drv)?;
+        self.write(mode)?;
+        self.process_stderr()?;
+        self.decode()
+    }
+    /// like `query_path_info`, but fails unless one of `keys` signed the path
+    pub fn query_signed_path_info(
+        &mut self,
+        path: &StorePath,
+        keys: &[PublicKey],
+    ) -> Result<ValidPathInfo> {
+        let info = self.query_path_info(path)?;
+        if info.check_signatures(keys)? == 0 {
+            return Err(ClientError::Generic(format!(
+                "no valid signature on {}",
+                path
+            )));
+        }
+        Ok(info)
+    }
+    pub fn query_path_info(&mut self, path: &StorePath) -> Result<ValidPathInfo> {
         self.write(Op::QueryPathInfo)?;
         self.write(path)?;
         self.process_stderr()?;
-        let valid: bool = self.read()?;
-        if valid {
-            let deriver: String = self.read()?;
-            let hash: String = self.read()?;
-            let references: Vec<String> = self.read()?;
-            let registration_time: u64 = self.read()?;
-            let nar_size: u64 = self.read()?;
-            let ultimate: bool = self.read()?;
-            let sigs: Vec<String> = self.read()?;
-            let ca: String = self.read()?;
-            Ok(ValidPathInfo {
-                path: path.to_string(),
-                deriver: if deriver.is_empty() {
-                    None
-                } else {
-                    Some(deriver)
-                },
-                hash,
-                nar_size,
-                id: 0,
-                ca: if ca.is_empty() { None } else { Some(ca) },
-                references,
-                sigs,
-                ultimate,
-                registration_time,
-            })
-        } else {
-            Err(ClientError::Generic(String::from("invalid path")))
+        match self.decode::<Option<UnkeyedValidPathInfo>>()? {
+            Some(info) => Ok(ValidPathInfo {
+                path: path.clone(),
+                info,
+            }),
+            None => Err(ClientError::Generic(String::from("invalid path"))),
         }
     }
 }
 
 #[cfg(test)]
 mod test {
-    use super::daemon;
+    use super::{daemon, Client};
+    use crate::protocol::*;
+    use crate::ser::Serializer;
+    use serde::Serialize;
 
     #[test]
     fn test_daemon() {
         let mut client = daemon().unwrap();
     }
+
+    #[test]
+    fn test_daemon_version() {
+        let hello = |version: u64| {
+            let mut reply = vec![];
+            let mut ser = Serializer::new(&mut reply);
+            WORKER_MAGIC_2.serialize(&mut ser).unwrap();
+            version.serialize(&mut ser).unwrap();
+            if protocol_version_minor(version) >= 33 {
+                "nix (Nix) 2.18.1".serialize(&mut ser).unwrap();
+            }
+            if protocol_version_minor(version) >= 35 {
+                1_u64.serialize(&mut ser).unwrap();
+            }
+            STDERR_LAST.serialize(&mut ser).unwrap();
+            reply
+        };
+        let reply = hello(PROTOCOL_VERSION);
+        let client = Client::new(vec![], &reply[..]).unwrap();
+        assert_eq!(client.daemon_version(), Some("nix (Nix) 2.18.1"));
+        let reply = hello(protocol_version_major(PROTOCOL_VERSION) | 32);
+        let client = Client::new(vec![], &reply[..]).unwrap();
+        assert_eq!(client.daemon_version(), None);
+    }
 }
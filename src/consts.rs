@@ -1,6 +1,16 @@
 use serde_repr::{Deserialize_repr, Serialize_repr};
 
-#[derive(Serialize_repr, Deserialize_repr, Debug)]
+pub const STORE_DIR: &str = "/nix/store";
+
+#[derive(Serialize_repr, Deserialize_repr, Debug, PartialEq, Eq, Clone, Copy)]
+#[repr(u64)]
+pub enum BuildMode {
+    Normal,
+    Repair,
+    Check,
+}
+
+#[derive(Serialize_repr, Deserialize_repr, Debug, PartialEq, Eq, Clone, Copy)]
 #[repr(u64)]
 pub enum BuildStatus {
     Built,
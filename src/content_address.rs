@@ -0,0 +1,252 @@
+//! content addresses of store paths, the `ca` field of path infos
+//!
+//! adapated from <https://github.com/NixOS/nix/blob/master/src/libstore/content-address.cc>
+
+use crate::de::WorkerDecode;
+use crate::error::{Error, Result};
+use crate::hash::{Hash, HashAlgo, HashFormat};
+use crate::ser::WorkerEncode;
+use crate::store_path::{FileIngestionMethod, StorePath};
+use serde::{Deserialize, Serialize};
+
+/// rendered as `text:sha256:<nix32>`, `fixed:<algo>:<nix32>` or
+/// `fixed:r:<algo>:<nix32>`
+#[derive(Clone, Debug, PartialEq, Eq, std::hash::Hash)]
+pub enum ContentAddress {
+    /// a text file like from `builtins.toFile`, hashed with sha256
+    Text(Hash),
+    Fixed {
+        method: FileIngestionMethod,
+        hash: Hash,
+    },
+}
+
+impl ContentAddress {
+    pub fn hash(&self) -> &Hash {
+        match self {
+            ContentAddress::Text(hash) | ContentAddress::Fixed { hash, .. } => hash,
+        }
+    }
+    /// the store path with this content address
+    pub fn store_path(
+        &self,
+        name: &str,
+        references: &[StorePath],
+        self_reference: bool,
+    ) -> Result<StorePath> {
+        match self {
+            ContentAddress::Text(_) if self_reference => Err(Error::Message(String::from(
+                "text paths can not refer to themselves",
+            ))),
+            ContentAddress::Text(hash) => StorePath::make_text(name, hash, references),
+            ContentAddress::Fixed { method, hash } => {
+                StorePath::make_fixed_output(name, *method, hash, references, self_reference)
+            }
+        }
+    }
+    /// check that `path` is the store path implied by this content address,
+    /// `references` may include `path` itself
+    pub fn check(&self, path: &StorePath, references: &[StorePath]) -> Result<()> {
+        let others: Vec<StorePath> = references.iter().filter(|x| *x != path).cloned().collect();
+        let expected = self.store_path(path.name(), &others, others.len() != references.len())?;
+        if &expected != path {
+            return Err(Error::Message(format!(
+                "path {} does not match content address {}, expected {}",
+                path, self, expected
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl std::str::FromStr for ContentAddress {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = |reason| Error::InvalidContentAddress {
+            ca: s.to_string(),
+            reason,
+        };
+        let (kind, rest) = s.split_once(':').ok_or_else(|| invalid("missing prefix"))?;
+        // the hash needs its algorithm, SRI is not accepted
+        let parse_hash = |s: &str| match s.split_once(':') {
+            Some((algo, _)) => Hash::parse_any(s, Some(algo.parse()?)),
+            None => Err(invalid("missing hash algorithm")),
+        };
+        match kind {
+            "text" => {
+                let hash = parse_hash(rest)?;
+                if hash.algo() != HashAlgo::Sha256 {
+                    return Err(invalid("text hashes must be sha256"));
+                }
+                Ok(ContentAddress::Text(hash))
+            }
+            "fixed" => {
+                let (method, rest) = match rest.strip_prefix("r:") {
+                    Some(rest) => (FileIngestionMethod::Recursive, rest),
+                    None => (FileIngestionMethod::Flat, rest),
+                };
+                Ok(ContentAddress::Fixed {
+                    method,
+                    hash: parse_hash(rest)?,
+                })
+            }
+            _ => Err(invalid("unknown prefix")),
+        }
+    }
+}
+
+impl std::fmt::Display for ContentAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ContentAddress::Text(hash) => {
+                write!(f, "text:{}", hash.to_prefixed(HashFormat::Nix32))
+            }
+            ContentAddress::Fixed { method, hash } => write!(
+                f,
+                "fixed:{}{}",
+                match method {
+                    FileIngestionMethod::Flat => "",
+                    FileIngestionMethod::Recursive => "r:",
+                },
+                hash.to_prefixed(HashFormat::Nix32)
+            ),
+        }
+    }
+}
+
+impl Serialize for ContentAddress {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for ContentAddress {
+    fn deserialize<D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+impl WorkerEncode for ContentAddress {
+    fn encode<W: std::io::Write>(&self, ser: &mut crate::ser::Serializer<W>) -> Result<()> {
+        self.to_string().encode(ser)
+    }
+}
+
+impl WorkerDecode for ContentAddress {
+    fn decode<R: std::io::Read>(de: &mut crate::de::Deserializer<R>) -> Result<Self> {
+        de.decode::<String>()?.parse()
+    }
+}
+
+crate::de::decode_async_as_string!(ContentAddress);
+
+#[test]
+fn test_parse() {
+    let sha256 = "1b8m03r63zqhnjf7l5wnldhh7c134ap5vpj0850ymkq1iyzicy5s";
+    let hash: Hash = format!("sha256:{}", sha256).parse().unwrap();
+    for (s, ca) in [
+        (
+            format!("text:sha256:{}", sha256),
+            ContentAddress::Text(hash.clone()),
+        ),
+        (
+            format!("fixed:sha256:{}", sha256),
+            ContentAddress::Fixed {
+                method: FileIngestionMethod::Flat,
+                hash: hash.clone(),
+            },
+        ),
+        (
+            format!("fixed:r:sha256:{}", sha256),
+            ContentAddress::Fixed {
+                method: FileIngestionMethod::Recursive,
+                hash: hash.clone(),
+            },
+        ),
+        (
+            String::from("fixed:r:sha1:kpcd173cq987hw957sx6m0868wv3x6d9"),
+            ContentAddress::Fixed {
+                method: FileIngestionMethod::Recursive,
+                hash: "sha1:a9993e364706816aba3e25717850c26c9cd0d89d"
+                    .parse()
+                    .unwrap(),
+            },
+        ),
+    ] {
+        assert_eq!(s.parse::<ContentAddress>().unwrap(), ca);
+        assert_eq!(ca.to_string(), s);
+    }
+    // other digest formats are accepted but rendered as nix32
+    assert_eq!(
+        format!("fixed:r:{}", hash.to_prefixed(HashFormat::Base16))
+            .parse::<ContentAddress>()
+            .unwrap()
+            .to_string(),
+        format!("fixed:r:sha256:{}", sha256)
+    );
+    for (s, reason) in [
+        ("sha256:abc", "unknown prefix"),
+        ("text", "missing prefix"),
+        (
+            "fixed:r:1b8m03r63zqhnjf7l5wnldhh7c134ap5vpj0850ymkq1iyzicy5s",
+            "missing hash algorithm",
+        ),
+        (
+            "text:sha1:kpcd173cq987hw957sx6m0868wv3x6d9",
+            "text hashes must be sha256",
+        ),
+    ] {
+        match s.parse::<ContentAddress>() {
+            Err(Error::InvalidContentAddress { reason: r, .. }) => assert_eq!(r, reason, "{}", s),
+            res => panic!("{}: {:?}", s, res),
+        }
+    }
+    assert!(matches!(
+        "fixed:sha256-ungWv48Bz+pBQUDeXa4iI7ADYaOWF3qctBD/YfIAFa0=".parse::<ContentAddress>(),
+        Err(Error::InvalidContentAddress { .. })
+    ));
+}
+
+#[test]
+fn test_check() {
+    // pkgs.hello.src
+    let ca: ContentAddress = "fixed:sha256:086vqwk2wl8zfs47sq2xpjc9k066ilmb8z6dn0q6ymwjzlm196cd"
+        .parse()
+        .unwrap();
+    let path: StorePath = "/nix/store/pa10z4ngm0g83kx9mssrqzz30s84vq7k-hello-2.12.1.tar.gz"
+        .parse()
+        .unwrap();
+    assert_eq!(ca.store_path(path.name(), &[], false).unwrap(), path);
+    ca.check(&path, &[]).unwrap();
+    let other: StorePath = "/nix/store/5yyljxwfkck991s0vp592v2afcyhya9k-hello-2.12.1.tar.gz"
+        .parse()
+        .unwrap();
+    assert!(ca.check(&other, &[]).is_err());
+    assert!(ca.check(&path, std::slice::from_ref(&other)).is_err());
+
+    let hash = Hash::sha256(b"echo hi");
+    let glibc: StorePath = "/nix/store/9lkz5r8b5srq6z3x5nzi1k8d5mgr4z16-glibc-2.35"
+        .parse()
+        .unwrap();
+    let text = ContentAddress::Text(hash.clone());
+    let path = text
+        .store_path("builder.sh", std::slice::from_ref(&glibc), false)
+        .unwrap();
+    text.check(&path, &[glibc]).unwrap();
+    assert!(text.store_path("builder.sh", &[], true).is_err());
+
+    let fixed = ContentAddress::Fixed {
+        method: FileIngestionMethod::Recursive,
+        hash,
+    };
+    let path = fixed.store_path("source", &[], true).unwrap();
+    fixed.check(&path, std::slice::from_ref(&path)).unwrap();
+    assert!(fixed.check(&path, &[]).is_err());
+}
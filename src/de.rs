@@ -1,16 +1,82 @@
 use crate::error::Error;
+use crate::protocol::PROTOCOL_VERSION;
+use crate::ser::{StringTagged, STRING_TAGGED};
 
 use serde::de;
 use serde::Deserialize;
+pub use sirius_derive::WorkerDecode;
+
+/// upper bounds on what a peer can make the deserializer allocate
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limits {
+    /// length of a single string or byte buffer
+    pub max_string_len: u64,
+    /// number of elements announced for a sequence or map
+    pub max_seq_len: u64,
+    /// bytes read over the lifetime of the deserializer, payloads read
+    /// through [`Deserializer::deserialize_reader`] do not count
+    pub max_total_bytes: u64,
+}
+
+impl Limits {
+    pub const UNLIMITED: Limits = Limits {
+        max_string_len: u64::MAX,
+        max_seq_len: u64::MAX,
+        max_total_bytes: u64::MAX,
+    };
+    /// defaults for reading from untrusted clients
+    pub const SERVER: Limits = Limits {
+        max_string_len: 64 << 20,
+        max_seq_len: 1 << 20,
+        max_total_bytes: 256 << 20,
+    };
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self::UNLIMITED
+    }
+}
+
+fn check_limit(what: &'static str, value: u64, limit: u64) -> crate::error::Result<()> {
+    if value > limit {
+        return Err(Error::LimitExceeded { what, value, limit });
+    }
+    Ok(())
+}
+
+enum Source<'de, R> {
+    Read(&'de mut R),
+    Slice(&'de [u8]),
+}
 
 pub struct Deserializer<'de, R> {
-    read: &'de mut R,
+    source: Source<'de, R>,
+    version: u64,
+    limits: Limits,
+    string_tag: bool, // set by `StringTagged`, cleared by anything but a variant
+    offset: u64,      // bytes consumed so far
+    start: u64,       // offset of the value being parsed
+    streamed: u64,    // bytes of offset read through a BytesReader
+    depth: usize,
 }
 
-macro_rules! deserialize_unimplemented {
-    ($name:ident) => {
+macro_rules! deserialize_unsupported {
+    ($name:ident, $what:expr) => {
         fn $name<V: de::Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
-            unimplemented!()
+            Err(Error::Unsupported($what))
+        }
+    };
+}
+
+macro_rules! deserialize_integer {
+    ($name:ident, $visit:ident, $ty:ty) => {
+        fn $name<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+            let value = self.parse_u64()?;
+            visitor.$visit(<$ty>::try_from(value).map_err(|_| Error::OutOfRange {
+                ty: stringify!($ty),
+                value,
+            })?)
         }
     };
 }
@@ -18,6 +84,25 @@ macro_rules! deserialize_unimplemented {
 pub struct SeqDeserializer<'a, 'de: 'a, T> {
     de: &'a mut Deserializer<'de, T>,
     remain: u64,
+    index: u64,
+    fields: &'static [&'static str],
+}
+
+impl<'a, 'de: 'a, R> SeqDeserializer<'a, 'de, R> {
+    fn new(de: &'a mut Deserializer<'de, R>, len: u64) -> Self {
+        Self {
+            de,
+            remain: len,
+            index: 0,
+            fields: &[],
+        }
+    }
+    fn segment(&self, index: u64) -> String {
+        match usize::try_from(index).ok().and_then(|i| self.fields.get(i)) {
+            Some(field) => format!(".{}", field),
+            None => format!("[{}]", index),
+        }
+    }
 }
 
 impl<'de, 'a, R: std::io::Read> de::SeqAccess<'de> for SeqDeserializer<'a, 'de, R> {
@@ -30,7 +115,93 @@ impl<'de, 'a, R: std::io::Read> de::SeqAccess<'de> for SeqDeserializer<'a, 'de,
             return Ok(None);
         }
         self.remain -= 1;
-        seed.deserialize(&mut *self.de).map(Some)
+        self.index += 1;
+        seed.deserialize(&mut *self.de)
+            .map(Some)
+            .map_err(|e| e.context(&self.segment(self.index - 1), self.de.start))
+    }
+    fn size_hint(&self) -> Option<usize> {
+        self.remain.try_into().ok()
+    }
+}
+
+impl<'de, 'a, R: std::io::Read> de::MapAccess<'de> for SeqDeserializer<'a, 'de, R> {
+    type Error = Error;
+    fn next_key_seed<K>(&mut self, seed: K) -> Result<Option<K::Value>, Self::Error>
+    where
+        K: de::DeserializeSeed<'de>,
+    {
+        if self.remain == 0 {
+            return Ok(None);
+        }
+        self.remain -= 1;
+        self.index += 1;
+        seed.deserialize(&mut *self.de)
+            .map(Some)
+            .map_err(|e| e.context(&self.segment(self.index - 1), self.de.start))
+    }
+    fn next_value_seed<V>(&mut self, seed: V) -> Result<V::Value, Self::Error>
+    where
+        V: de::DeserializeSeed<'de>,
+    {
+        seed.deserialize(&mut *self.de)
+            .map_err(|e| e.context(&self.segment(self.index - 1), self.de.start))
+    }
+    fn size_hint(&self) -> Option<usize> {
+        self.remain.try_into().ok()
+    }
+}
+
+pub struct EnumDeserializer<'a, 'de: 'a, T> {
+    de: &'a mut Deserializer<'de, T>,
+}
+
+impl<'de, 'a, R: std::io::Read> de::EnumAccess<'de> for EnumDeserializer<'a, 'de, R> {
+    type Error = Error;
+    type Variant = Self;
+    fn variant_seed<V>(self, seed: V) -> Result<(V::Value, Self::Variant), Self::Error>
+    where
+        V: de::DeserializeSeed<'de>,
+    {
+        use de::IntoDeserializer;
+        let variant = if std::mem::take(&mut self.de.string_tag) {
+            let name: de::value::StringDeserializer<Error> =
+                self.de.parse_string()?.into_deserializer();
+            seed.deserialize(name)?
+        } else {
+            let index: de::value::U64Deserializer<Error> = self.de.parse_u64()?.into_deserializer();
+            seed.deserialize(index)?
+        };
+        Ok((variant, self))
+    }
+}
+
+impl<'de, 'a, R: std::io::Read> de::VariantAccess<'de> for EnumDeserializer<'a, 'de, R> {
+    type Error = Error;
+    fn unit_variant(self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+    fn newtype_variant_seed<T>(self, seed: T) -> Result<T::Value, Self::Error>
+    where
+        T: de::DeserializeSeed<'de>,
+    {
+        seed.deserialize(&mut *self.de)
+    }
+    fn tuple_variant<V>(self, len: usize, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        de::Deserializer::deserialize_tuple(&mut *self.de, len, visitor)
+    }
+    fn struct_variant<V>(
+        self,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error>
+    where
+        V: de::Visitor<'de>,
+    {
+        de::Deserializer::deserialize_tuple(&mut *self.de, fields.len(), visitor)
     }
 }
 
@@ -39,32 +210,165 @@ where
     R: std::io::Read,
 {
     pub fn new(read: &'de mut R) -> Self {
-        Self { read }
+        Self::with_version(read, PROTOCOL_VERSION)
+    }
+    pub fn with_version(read: &'de mut R, version: u64) -> Self {
+        Self::with_source(Source::Read(read), version)
+    }
+    fn with_source(source: Source<'de, R>, version: u64) -> Self {
+        Self {
+            source,
+            version,
+            limits: Limits::default(),
+            string_tag: false,
+            offset: 0,
+            start: 0,
+            streamed: 0,
+            depth: 0,
+        }
+    }
+    /// protocol version negotiated with the peer
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+    /// number of bytes consumed since this deserializer was created
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+    fn reserve(&mut self, len: u64) -> crate::error::Result<()> {
+        check_limit(
+            "total bytes",
+            (self.offset - self.streamed).saturating_add(len),
+            self.limits.max_total_bytes,
+        )
+    }
+    pub fn decode<T: WorkerDecode>(&mut self) -> crate::error::Result<T> {
+        T::decode(self)
+    }
+    /// add `segment`, like `.field` or `[3]`, to the field path of an error
+    /// from decoding the value that was just read
+    pub fn context(&self, error: Error, segment: &str) -> Error {
+        error.context(segment, self.start)
+    }
+    /// decode a struct field, errors get `.{name}` in their path
+    pub fn decode_field<T: WorkerDecode>(&mut self, name: &str) -> crate::error::Result<T> {
+        T::decode(self).map_err(|e| self.context(e, &format!(".{}", name)))
+    }
+    fn read_exact(&mut self, buf: &mut [u8]) -> crate::error::Result<()> {
+        Ok(self.read_exact_io(buf)?)
+    }
+    fn read_exact_io(&mut self, buf: &mut [u8]) -> std::io::Result<()> {
+        match &mut self.source {
+            Source::Read(read) => read.read_exact(buf),
+            Source::Slice(slice) => std::io::Read::read_exact(slice, buf),
+        }
+    }
+    fn read_some(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match &mut self.source {
+            Source::Read(read) => read.read(buf),
+            Source::Slice(slice) => std::io::Read::read(slice, buf),
+        }
+    }
+    /// bounded reader over the payload of a length-prefixed byte field
+    ///
+    /// the payload is not buffered and none of the [`Limits`] apply to it,
+    /// not even `max_total_bytes`, callers bound it themselves if need be. it
+    /// has to be read to the end before decoding anything else, the padding
+    /// after it is consumed along with its last byte
+    pub fn deserialize_reader(&mut self) -> crate::error::Result<BytesReader<'_, 'de, R>> {
+        let len = self.parse_u64()?;
+        let rem = len % 8;
+        let pad = if rem == 0 { 0 } else { 8 - rem };
+        Ok(BytesReader {
+            de: self,
+            remain: len,
+            pad: pad as usize,
+            error: None,
+        })
     }
     fn parse_u64(&mut self) -> crate::error::Result<u64> {
+        self.string_tag = false;
         let mut buf: [u8; 8] = [0; 8];
-        self.read.read_exact(&mut buf)?;
+        self.start = self.offset;
+        self.reserve(8)?;
+        self.read_exact(&mut buf)?;
+        self.offset += 8;
         Ok(u64::from_le_bytes(buf))
     }
     fn parse_bool(&mut self) -> crate::error::Result<bool> {
         let num = self.parse_u64()?;
         Ok(num != 0)
     }
-    fn parse_bytes(&mut self) -> crate::error::Result<Vec<u8>> {
-        let len: usize = self.parse_u64()?.try_into()?;
+    fn parse_len(&mut self) -> crate::error::Result<u64> {
+        let len = self.parse_u64()?;
+        check_limit("sequence length", len, self.limits.max_seq_len)?;
+        Ok(len)
+    }
+    /// borrows from the input in slice mode, copies otherwise
+    fn parse_cow_bytes(&mut self) -> crate::error::Result<std::borrow::Cow<'de, [u8]>> {
+        let len = self.parse_u64()?;
+        let start = self.start;
+        check_limit("string length", len, self.limits.max_string_len)?;
+        let len: usize = len.try_into().map_err(|_| Error::OutOfRange {
+            ty: "usize",
+            value: len,
+        })?;
         let rem = len % 8;
         let pad = if rem == 0 { 0 } else { 8 - rem };
-        let mut buf = vec![0; len + pad];
-        self.read.read_exact(&mut buf)?;
-        buf.truncate(len);
+        self.reserve((len + pad) as u64)?;
+        let buf = match &mut self.source {
+            Source::Slice(slice) if slice.len() >= len + pad => {
+                let (buf, rest) = slice.split_at(len + pad);
+                *slice = rest;
+                std::borrow::Cow::Borrowed(&buf[..len])
+            }
+            _ => {
+                let mut buf = vec![0; len + pad];
+                self.read_exact(&mut buf)?;
+                buf.truncate(len);
+                std::borrow::Cow::Owned(buf)
+            }
+        };
+        self.offset += (len + pad) as u64;
+        self.start = start;
         Ok(buf)
     }
+    fn parse_bytes(&mut self) -> crate::error::Result<Vec<u8>> {
+        Ok(self.parse_cow_bytes()?.into_owned())
+    }
     fn parse_string(&mut self) -> crate::error::Result<String> {
         let buf = self.parse_bytes()?;
         Ok(String::from_utf8(buf)?)
     }
 }
 
+impl<'de> Deserializer<'de, &'de [u8]> {
+    /// deserializer over an in-memory buffer
+    ///
+    /// strings and byte slices can be borrowed from `buf` by deserializing
+    /// into `&'de str` and `&'de [u8]` instead of owned values
+    pub fn from_slice(buf: &'de [u8]) -> Self {
+        Self::from_slice_with_version(buf, PROTOCOL_VERSION)
+    }
+    pub fn from_slice_with_version(buf: &'de [u8], version: u64) -> Self {
+        Self::with_source(Source::Slice(buf), version)
+    }
+    /// input not consumed yet
+    pub fn remaining(&self) -> &'de [u8] {
+        match &self.source {
+            Source::Slice(slice) => slice,
+            Source::Read(_) => &[],
+        }
+    }
+}
+
 impl<'de, 'a, R> de::Deserializer<'de> for &'a mut Deserializer<'de, R>
 where
     R: std::io::Read,
@@ -73,6 +377,13 @@ where
     fn deserialize_u64<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
         visitor.visit_u64(self.parse_u64()?)
     }
+    deserialize_integer!(deserialize_u8, visit_u8, u8);
+    deserialize_integer!(deserialize_u16, visit_u16, u16);
+    deserialize_integer!(deserialize_u32, visit_u32, u32);
+    deserialize_integer!(deserialize_i8, visit_i8, i8);
+    deserialize_integer!(deserialize_i16, visit_i16, i16);
+    deserialize_integer!(deserialize_i32, visit_i32, i32);
+    deserialize_integer!(deserialize_i64, visit_i64, i64);
     fn deserialize_bool<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
         visitor.visit_bool(self.parse_bool()?)
     }
@@ -90,35 +401,61 @@ where
     ) -> Result<V::Value, Self::Error> {
         visitor.visit_byte_buf(self.parse_bytes()?)
     }
+    fn deserialize_bytes<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self.parse_cow_bytes()? {
+            std::borrow::Cow::Borrowed(buf) => visitor.visit_borrowed_bytes(buf),
+            std::borrow::Cow::Owned(buf) => visitor.visit_byte_buf(buf),
+        }
+    }
     fn deserialize_string<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
         visitor.visit_string(self.parse_string()?)
     }
+    fn deserialize_str<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self.parse_cow_bytes()? {
+            std::borrow::Cow::Borrowed(buf) => match std::str::from_utf8(buf) {
+                Ok(s) => visitor.visit_borrowed_str(s),
+                Err(e) => Err(e.into()),
+            },
+            std::borrow::Cow::Owned(buf) => visitor.visit_string(String::from_utf8(buf)?),
+        }
+    }
+    fn deserialize_unit<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        self.string_tag = false;
+        visitor.visit_unit()
+    }
     fn deserialize_seq<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
-        let len = self.parse_u64()?;
-        let seq = SeqDeserializer {
-            de: self,
-            remain: len,
-        };
-        visitor.visit_seq(seq)
+        let len = self.parse_len()?;
+        visitor.visit_seq(SeqDeserializer::new(self, len))
+    }
+    fn deserialize_map<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        let len = self.parse_len()?;
+        visitor.visit_map(SeqDeserializer::new(self, len))
     }
     fn deserialize_tuple<V: de::Visitor<'de>>(
         self,
         len: usize,
         visitor: V,
     ) -> Result<V::Value, Self::Error> {
-        let seq = SeqDeserializer {
-            de: self,
-            remain: len.try_into()?,
-        };
-        visitor.visit_seq(seq)
+        self.string_tag = false;
+        visitor.visit_seq(SeqDeserializer::new(self, len as u64))
     }
     fn deserialize_struct<V: de::Visitor<'de>>(
         self,
-        _name: &'static str,
+        name: &'static str,
         fields: &'static [&'static str],
         visitor: V,
     ) -> Result<V::Value, Self::Error> {
-        self.deserialize_tuple(fields.len(), visitor)
+        self.string_tag = false;
+        let mut seq = SeqDeserializer::new(self, fields.len() as u64);
+        seq.fields = fields;
+        seq.de.depth += 1;
+        let res = visitor.visit_seq(&mut seq);
+        seq.de.depth -= 1;
+        // only the outermost struct names the path, nested ones are named by their field
+        match res {
+            Err(e) if seq.de.depth == 0 => Err(e.context(name, seq.de.start)),
+            res => res,
+        }
     }
     fn deserialize_tuple_struct<V: de::Visitor<'de>>(
         self,
@@ -128,44 +465,246 @@ where
     ) -> Result<V::Value, Self::Error> {
         self.deserialize_tuple(len, visitor)
     }
-    deserialize_unimplemented!(deserialize_str);
-    deserialize_unimplemented!(deserialize_any);
-    deserialize_unimplemented!(deserialize_i8);
-    deserialize_unimplemented!(deserialize_i16);
-    deserialize_unimplemented!(deserialize_i32);
-    deserialize_unimplemented!(deserialize_i64);
-    deserialize_unimplemented!(deserialize_u8);
-    deserialize_unimplemented!(deserialize_u16);
-    deserialize_unimplemented!(deserialize_u32);
-    deserialize_unimplemented!(deserialize_f32);
-    deserialize_unimplemented!(deserialize_f64);
-    deserialize_unimplemented!(deserialize_char);
-    deserialize_unimplemented!(deserialize_bytes);
-    deserialize_unimplemented!(deserialize_unit);
-    deserialize_unimplemented!(deserialize_map);
-    deserialize_unimplemented!(deserialize_identifier);
-    deserialize_unimplemented!(deserialize_ignored_any);
+    deserialize_unsupported!(deserialize_any, "self-describing deserialization");
+    deserialize_unsupported!(deserialize_f32, "f32");
+    deserialize_unsupported!(deserialize_f64, "f64");
+    deserialize_unsupported!(deserialize_char, "char");
+    deserialize_unsupported!(deserialize_identifier, "identifier");
+    deserialize_unsupported!(deserialize_ignored_any, "skipping values");
     fn deserialize_unit_struct<V: de::Visitor<'de>>(
         self,
         _name: &'static str,
-        _visitor: V,
+        visitor: V,
     ) -> Result<V::Value, Self::Error> {
-        unimplemented!()
+        self.string_tag = false;
+        visitor.visit_unit()
     }
     fn deserialize_newtype_struct<V: de::Visitor<'de>>(
         self,
-        _name: &'static str,
-        _visitor: V,
+        name: &'static str,
+        visitor: V,
     ) -> Result<V::Value, Self::Error> {
-        unimplemented!()
+        self.string_tag = name == STRING_TAGGED;
+        let res = visitor.visit_newtype_struct(&mut *self);
+        self.string_tag = false;
+        res
     }
     fn deserialize_enum<V: de::Visitor<'de>>(
         self,
         _name: &'static str,
         _variants: &'static [&'static str],
-        _visitor: V,
+        visitor: V,
     ) -> Result<V::Value, Self::Error> {
-        unimplemented!()
+        visitor.visit_enum(EnumDeserializer { de: self })
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for StringTagged<T> {
+    fn deserialize<D: de::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor<T>(std::marker::PhantomData<T>);
+        impl<'de, T: Deserialize<'de>> de::Visitor<'de> for Visitor<T> {
+            type Value = StringTagged<T>;
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("a string tagged enum")
+            }
+            fn visit_newtype_struct<D: de::Deserializer<'de>>(
+                self,
+                deserializer: D,
+            ) -> Result<Self::Value, D::Error> {
+                T::deserialize(deserializer).map(StringTagged)
+            }
+        }
+        deserializer.deserialize_newtype_struct(STRING_TAGGED, Visitor(std::marker::PhantomData))
+    }
+}
+
+/// wire decoding with access to the negotiated protocol version
+///
+/// counterpart of [`crate::ser::WorkerEncode`]
+pub trait WorkerDecode: Sized {
+    fn decode<R: std::io::Read>(de: &mut Deserializer<R>) -> crate::error::Result<Self>;
+}
+
+macro_rules! implement_worker_decode {
+    ($($ty:ty),*) => {
+        $(
+            impl WorkerDecode for $ty {
+                fn decode<R: std::io::Read>(
+                    de: &mut Deserializer<R>,
+                ) -> crate::error::Result<Self> {
+                    Self::deserialize(de)
+                }
+            }
+        )*
+    };
+}
+
+implement_worker_decode!(u64, bool, String);
+
+impl<T: WorkerDecode> WorkerDecode for Option<T> {
+    fn decode<R: std::io::Read>(de: &mut Deserializer<R>) -> crate::error::Result<Self> {
+        if de.parse_bool()? {
+            Ok(Some(T::decode(de)?))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl<T: WorkerDecode> WorkerDecode for Vec<T> {
+    fn decode<R: std::io::Read>(de: &mut Deserializer<R>) -> crate::error::Result<Self> {
+        let len = de.parse_len()?;
+        (0..len)
+            .map(|i| T::decode(de).map_err(|e| de.context(e, &format!("[{}]", i))))
+            .collect()
+    }
+}
+
+impl<K, V, S> WorkerDecode for std::collections::HashMap<K, V, S>
+where
+    K: WorkerDecode + Eq + std::hash::Hash,
+    V: WorkerDecode,
+    S: std::hash::BuildHasher + Default,
+{
+    fn decode<R: std::io::Read>(de: &mut Deserializer<R>) -> crate::error::Result<Self> {
+        let len = de.parse_len()?;
+        (0..len)
+            .map(|i| {
+                let segment = || format!("[{}]", i);
+                let key = K::decode(de).map_err(|e| de.context(e, &segment()))?;
+                let value = V::decode(de).map_err(|e| de.context(e, &segment()))?;
+                Ok((key, value))
+            })
+            .collect()
+    }
+}
+
+impl<A: WorkerDecode, B: WorkerDecode> WorkerDecode for (A, B) {
+    fn decode<R: std::io::Read>(de: &mut Deserializer<R>) -> crate::error::Result<Self> {
+        Ok((A::decode(de)?, B::decode(de)?))
+    }
+}
+
+#[doc(hidden)]
+pub fn decode_serde<T: de::DeserializeOwned, R: std::io::Read>(
+    de: &mut Deserializer<R>,
+) -> crate::error::Result<T> {
+    T::deserialize(de)
+}
+
+/// decode a struct, only the outermost one is named in the path of errors,
+/// nested ones are named by their field like with serde
+#[doc(hidden)]
+pub fn decode_struct<T, R: std::io::Read>(
+    de: &mut Deserializer<R>,
+    name: &str,
+    decode: impl FnOnce(&mut Deserializer<R>) -> crate::error::Result<T>,
+) -> crate::error::Result<T> {
+    de.depth += 1;
+    let res = decode(de);
+    de.depth -= 1;
+    match res {
+        Err(e) if de.depth == 0 => Err(de.context(e, name)),
+        res => res,
+    }
+}
+
+#[doc(hidden)]
+pub fn decode_empty_as_none<T, R: std::io::Read>(
+    de: &mut Deserializer<R>,
+) -> crate::error::Result<Option<T>>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    parse_empty_as_none(de.parse_string()?)
+}
+
+fn parse_empty_as_none<T>(s: String) -> crate::error::Result<Option<T>>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    if s.is_empty() {
+        return Ok(None);
+    }
+    s.parse()
+        .map(Some)
+        .map_err(|e| Error::Message(format!("invalid value {:?}: {}", s, e)))
+}
+
+#[doc(hidden)]
+pub fn decode_store_path<R: std::io::Read>(
+    de: &mut Deserializer<R>,
+) -> crate::error::Result<String> {
+    strip_store_dir(de.parse_string()?)
+}
+
+fn strip_store_dir(s: String) -> crate::error::Result<String> {
+    s.strip_prefix(crate::consts::STORE_DIR)
+        .and_then(|x| x.strip_prefix('/'))
+        .map(str::to_string)
+        .ok_or_else(|| Error::Message(format!("path {:?} not in store", s)))
+}
+
+/// see [`Deserializer::deserialize_reader`]
+///
+/// non-zero padding is reported by the read after the one that returned the
+/// last byte of the payload, or by [`BytesReader::finish`]
+pub struct BytesReader<'a, 'de, R> {
+    de: &'a mut Deserializer<'de, R>,
+    remain: u64,
+    pad: usize,
+    // bad padding, reported by the read after the one that ended the payload
+    error: Option<std::io::Error>,
+}
+
+impl<'a, 'de, R: std::io::Read> BytesReader<'a, 'de, R> {
+    /// number of payload bytes not read yet
+    pub fn remaining(&self) -> u64 {
+        self.remain
+    }
+    /// skip the rest of the payload
+    pub fn finish(mut self) -> crate::error::Result<()> {
+        std::io::copy(&mut self, &mut std::io::sink())?;
+        Ok(())
+    }
+    fn read_padding(&mut self) -> std::result::Result<(), std::io::Error> {
+        let mut pad = [0; 8];
+        let pad = &mut pad[..std::mem::take(&mut self.pad)];
+        self.de.read_exact_io(pad)?;
+        self.de.offset += pad.len() as u64;
+        self.de.streamed += pad.len() as u64;
+        if pad.iter().any(|x| *x != 0) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "non-zero padding",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl<'a, 'de, R: std::io::Read> std::io::Read for BytesReader<'a, 'de, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::result::Result<usize, std::io::Error> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        let len = std::cmp::min(buf.len() as u64, self.remain) as usize;
+        if len == 0 {
+            return Ok(0);
+        }
+        let size = self.de.read_some(&mut buf[..len])?;
+        if size == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        self.remain -= size as u64;
+        self.de.offset += size as u64;
+        self.de.streamed += size as u64;
+        if self.remain == 0 {
+            self.error = self.read_padding().err();
+        }
+        Ok(size)
     }
 }
 
@@ -183,10 +722,8 @@ impl<'a, R> FramedReader<'a, R> {
 impl<'a, R: std::io::Read> std::io::Read for FramedReader<'a, R> {
     fn read(&mut self, buf: &mut [u8]) -> std::result::Result<usize, std::io::Error> {
         if self.rem == 0 {
-            self.rem = usize::deserialize(&mut Deserializer {
-                read: &mut self.read,
-            })
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+            self.rem = usize::deserialize(&mut Deserializer::new(&mut self.read))
+                .map_err(std::io::Error::other)?;
         }
         let size = self.read.take(self.rem.try_into().unwrap()).read(buf)?;
         self.rem -= size;
@@ -194,12 +731,389 @@ impl<'a, R: std::io::Read> std::io::Read for FramedReader<'a, R> {
     }
 }
 
+/// deserializer over a tokio [`tokio::io::AsyncRead`]
+///
+/// values are read a word or a string at a time through their
+/// [`AsyncWorkerDecode`], nothing is parsed twice. like with [`Deserializer`]
+/// the [`Limits`] apply over the lifetime of the deserializer and are checked
+/// before anything they cover is read.
+#[cfg(feature = "async")]
+pub struct AsyncDeserializer<'a, R> {
+    read: &'a mut R,
+    version: u64,
+    limits: Limits,
+    offset: u64, // bytes consumed so far
+    start: u64,  // offset of the value being parsed
+    depth: usize,
+}
+
+#[cfg(feature = "async")]
+impl<'a, R: tokio::io::AsyncRead + Unpin> AsyncDeserializer<'a, R> {
+    pub fn new(read: &'a mut R) -> Self {
+        Self::with_version(read, PROTOCOL_VERSION)
+    }
+    pub fn with_version(read: &'a mut R, version: u64) -> Self {
+        Self {
+            read,
+            version,
+            limits: Limits::default(),
+            offset: 0,
+            start: 0,
+            depth: 0,
+        }
+    }
+    /// protocol version negotiated with the peer
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+    /// number of bytes consumed since this deserializer was created
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+    /// add `segment`, like `.field` or `[3]`, to the field path of an error
+    /// from decoding the value that was just read
+    pub fn context(&self, error: Error, segment: &str) -> Error {
+        error.context(segment, self.start)
+    }
+    async fn read_exact(&mut self, buf: &mut [u8]) -> crate::error::Result<()> {
+        use tokio::io::AsyncReadExt;
+        check_limit(
+            "total bytes",
+            self.offset.saturating_add(buf.len() as u64),
+            self.limits.max_total_bytes,
+        )?;
+        self.read.read_exact(buf).await?;
+        self.offset += buf.len() as u64;
+        Ok(())
+    }
+    pub async fn read_u64(&mut self) -> crate::error::Result<u64> {
+        let mut buf: [u8; 8] = [0; 8];
+        self.start = self.offset;
+        self.read_exact(&mut buf).await?;
+        Ok(u64::from_le_bytes(buf))
+    }
+    pub async fn read_bool(&mut self) -> crate::error::Result<bool> {
+        Ok(self.read_u64().await? != 0)
+    }
+    async fn read_len(&mut self) -> crate::error::Result<u64> {
+        let len = self.read_u64().await?;
+        check_limit("sequence length", len, self.limits.max_seq_len)?;
+        Ok(len)
+    }
+    /// `header` zeroes followed by the payload of a byte field and its padding
+    async fn read_field(&mut self, header: usize) -> crate::error::Result<(Vec<u8>, usize)> {
+        let len = self.read_u64().await?;
+        let start = self.start;
+        check_limit("string length", len, self.limits.max_string_len)?;
+        let len: usize = len.try_into().map_err(|_| Error::OutOfRange {
+            ty: "usize",
+            value: len,
+        })?;
+        let rem = len % 8;
+        let pad = if rem == 0 { 0 } else { 8 - rem };
+        let mut buf = vec![0; header + len + pad];
+        self.read_exact(&mut buf[header..]).await?;
+        self.start = start;
+        Ok((buf, len))
+    }
+    pub async fn read_bytes(&mut self) -> crate::error::Result<Vec<u8>> {
+        let (mut buf, len) = self.read_field(0).await?;
+        buf.truncate(len);
+        Ok(buf)
+    }
+    pub async fn read_string(&mut self) -> crate::error::Result<String> {
+        Ok(String::from_utf8(self.read_bytes().await?)?)
+    }
+    /// decode a value sent as a single byte field, like a store path, by
+    /// reading the field and running its [`WorkerDecode`] over it
+    pub(crate) async fn decode_string_field<T: WorkerDecode>(&mut self) -> crate::error::Result<T> {
+        let (mut buf, len) = self.read_field(8).await?;
+        buf[..8].copy_from_slice(&(len as u64).to_le_bytes());
+        let mut de = Deserializer::from_slice_with_version(&buf, self.version);
+        de.offset = self.start;
+        T::decode(&mut de)
+    }
+    /// decode a value sent as a single word, like a fieldless enum, through
+    /// its serde implementation
+    pub(crate) async fn decode_serde_word<T: de::DeserializeOwned>(
+        &mut self,
+    ) -> crate::error::Result<T> {
+        let word = self.read_u64().await?.to_le_bytes();
+        let mut de = Deserializer::from_slice_with_version(&word, self.version);
+        de.offset = self.start;
+        T::deserialize(&mut de)
+    }
+    #[doc(hidden)]
+    pub fn enter_struct(&mut self) {
+        self.depth += 1;
+    }
+    /// only the outermost struct is named in the path of errors, nested ones
+    /// are named by their field like with [`decode_struct`]
+    #[doc(hidden)]
+    pub fn leave_struct<T>(
+        &mut self,
+        name: &str,
+        res: crate::error::Result<T>,
+    ) -> crate::error::Result<T> {
+        self.depth -= 1;
+        match res {
+            Err(e) if self.depth == 0 => Err(self.context(e, name)),
+            res => res,
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl<'a, R: tokio::io::AsyncRead + Unpin + Send> AsyncDeserializer<'a, R> {
+    pub async fn decode<T: AsyncWorkerDecode>(&mut self) -> crate::error::Result<T> {
+        T::decode_async(self).await
+    }
+    /// decode a struct field, errors get `.{name}` in their path
+    pub async fn decode_field<T: AsyncWorkerDecode>(
+        &mut self,
+        name: &str,
+    ) -> crate::error::Result<T> {
+        match T::decode_async(self).await {
+            Err(e) => Err(self.context(e, &format!(".{}", name))),
+            res => res,
+        }
+    }
+}
+
+/// [`WorkerDecode`] over an [`AsyncDeserializer`], reading the value as it
+/// arrives
+///
+/// derived along with [`WorkerDecode`] when the `async` feature is enabled
+#[cfg(feature = "async")]
+pub trait AsyncWorkerDecode: Sized {
+    fn decode_async<R: tokio::io::AsyncRead + Unpin + Send>(
+        de: &mut AsyncDeserializer<'_, R>,
+    ) -> impl std::future::Future<Output = crate::error::Result<Self>> + Send;
+}
+
+#[cfg(feature = "async")]
+#[doc(hidden)]
+pub use tokio::io::AsyncRead;
+
+/// expands to its input when sirius is built with the `async` feature, so
+/// the derive only emits [`AsyncWorkerDecode`] impls when the trait exists
+#[cfg(feature = "async")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __if_async {
+    ($($tt:tt)*) => { $($tt)* };
+}
+
+#[cfg(not(feature = "async"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __if_async {
+    ($($tt:tt)*) => {};
+}
+
+/// [`AsyncWorkerDecode`] for types sent as a single string, through
+/// [`AsyncDeserializer::decode_string_field`]
+macro_rules! decode_async_as_string {
+    ($($ty:ty),*) => {
+        $(
+            #[cfg(feature = "async")]
+            impl crate::de::AsyncWorkerDecode for $ty {
+                async fn decode_async<R: tokio::io::AsyncRead + Unpin + Send>(
+                    de: &mut crate::de::AsyncDeserializer<'_, R>,
+                ) -> crate::error::Result<Self> {
+                    de.decode_string_field().await
+                }
+            }
+        )*
+    };
+}
+pub(crate) use decode_async_as_string;
+
+#[cfg(feature = "async")]
+impl AsyncWorkerDecode for u64 {
+    async fn decode_async<R: tokio::io::AsyncRead + Unpin + Send>(
+        de: &mut AsyncDeserializer<'_, R>,
+    ) -> crate::error::Result<Self> {
+        de.read_u64().await
+    }
+}
+
+#[cfg(feature = "async")]
+impl AsyncWorkerDecode for bool {
+    async fn decode_async<R: tokio::io::AsyncRead + Unpin + Send>(
+        de: &mut AsyncDeserializer<'_, R>,
+    ) -> crate::error::Result<Self> {
+        de.read_bool().await
+    }
+}
+
+#[cfg(feature = "async")]
+impl AsyncWorkerDecode for String {
+    async fn decode_async<R: tokio::io::AsyncRead + Unpin + Send>(
+        de: &mut AsyncDeserializer<'_, R>,
+    ) -> crate::error::Result<Self> {
+        de.read_string().await
+    }
+}
+
+#[cfg(feature = "async")]
+impl<T: AsyncWorkerDecode + Send> AsyncWorkerDecode for Option<T> {
+    async fn decode_async<R: tokio::io::AsyncRead + Unpin + Send>(
+        de: &mut AsyncDeserializer<'_, R>,
+    ) -> crate::error::Result<Self> {
+        if de.read_bool().await? {
+            Ok(Some(T::decode_async(de).await?))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl<T: AsyncWorkerDecode + Send> AsyncWorkerDecode for Vec<T> {
+    async fn decode_async<R: tokio::io::AsyncRead + Unpin + Send>(
+        de: &mut AsyncDeserializer<'_, R>,
+    ) -> crate::error::Result<Self> {
+        let len = de.read_len().await?;
+        let mut res = vec![];
+        for i in 0..len {
+            match T::decode_async(de).await {
+                Ok(x) => res.push(x),
+                Err(e) => return Err(de.context(e, &format!("[{}]", i))),
+            }
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(feature = "async")]
+impl<K, V, S> AsyncWorkerDecode for std::collections::HashMap<K, V, S>
+where
+    K: AsyncWorkerDecode + Eq + std::hash::Hash + Send,
+    V: AsyncWorkerDecode + Send,
+    S: std::hash::BuildHasher + Default + Send,
+{
+    async fn decode_async<R: tokio::io::AsyncRead + Unpin + Send>(
+        de: &mut AsyncDeserializer<'_, R>,
+    ) -> crate::error::Result<Self> {
+        let len = de.read_len().await?;
+        let mut res = Self::default();
+        for i in 0..len {
+            let entry = match K::decode_async(de).await {
+                Ok(key) => V::decode_async(de).await.map(|value| (key, value)),
+                Err(e) => Err(e),
+            };
+            match entry {
+                Ok((key, value)) => res.insert(key, value),
+                Err(e) => return Err(de.context(e, &format!("[{}]", i))),
+            };
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(feature = "async")]
+impl<A: AsyncWorkerDecode + Send, B: AsyncWorkerDecode + Send> AsyncWorkerDecode for (A, B) {
+    async fn decode_async<R: tokio::io::AsyncRead + Unpin + Send>(
+        de: &mut AsyncDeserializer<'_, R>,
+    ) -> crate::error::Result<Self> {
+        Ok((A::decode_async(de).await?, B::decode_async(de).await?))
+    }
+}
+
+#[cfg(feature = "async")]
+#[doc(hidden)]
+pub async fn decode_empty_as_none_async<T, R>(
+    de: &mut AsyncDeserializer<'_, R>,
+) -> crate::error::Result<Option<T>>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+    R: tokio::io::AsyncRead + Unpin,
+{
+    parse_empty_as_none(de.read_string().await?)
+}
+
+#[cfg(feature = "async")]
+#[doc(hidden)]
+pub async fn decode_store_path_async<R: tokio::io::AsyncRead + Unpin>(
+    de: &mut AsyncDeserializer<'_, R>,
+) -> crate::error::Result<String> {
+    strip_store_dir(de.read_string().await?)
+}
+
+/// [`FramedReader`] over a tokio [`tokio::io::AsyncRead`]
+#[cfg(feature = "async")]
+pub struct AsyncFramedReader<'a, R> {
+    read: &'a mut R,
+    rem: usize,
+    header: [u8; 8],
+    filled: usize,
+    eof: bool,
+}
+
+#[cfg(feature = "async")]
+impl<'a, R> AsyncFramedReader<'a, R> {
+    pub fn new(read: &'a mut R) -> Self {
+        Self {
+            read,
+            rem: 0,
+            header: [0; 8],
+            filled: 0,
+            eof: false,
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl<'a, R: tokio::io::AsyncRead + Unpin> tokio::io::AsyncRead for AsyncFramedReader<'a, R> {
+    fn poll_read(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::result::Result<(), std::io::Error>> {
+        use std::pin::Pin;
+        use std::task::{ready, Poll};
+        let this = self.get_mut();
+        while this.rem == 0 {
+            if this.eof {
+                return Poll::Ready(Ok(()));
+            }
+            while this.filled < this.header.len() {
+                let mut header = tokio::io::ReadBuf::new(&mut this.header[this.filled..]);
+                ready!(Pin::new(&mut *this.read).poll_read(cx, &mut header))?;
+                let size = header.filled().len();
+                if size == 0 {
+                    return Poll::Ready(Err(std::io::ErrorKind::UnexpectedEof.into()));
+                }
+                this.filled += size;
+            }
+            this.filled = 0;
+            this.rem = u64::from_le_bytes(this.header)
+                .try_into()
+                .map_err(std::io::Error::other)?;
+            this.eof = this.rem == 0;
+        }
+        let size = std::cmp::min(buf.remaining(), this.rem);
+        let mut frame = tokio::io::ReadBuf::new(buf.initialize_unfilled_to(size));
+        ready!(Pin::new(&mut *this.read).poll_read(cx, &mut frame))?;
+        let size = frame.filled().len();
+        buf.advance(size);
+        this.rem -= size;
+        Poll::Ready(Ok(()))
+    }
+}
+
 #[test]
 fn test_u64() {
     let mut read: &[u8] = &[0x2a, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00][..];
     assert_eq!(
         42,
-        u64::deserialize(&mut Deserializer { read: &mut read }).unwrap()
+        u64::deserialize(&mut Deserializer::new(&mut read)).unwrap()
     );
 }
 
@@ -211,7 +1125,7 @@ fn test_string() {
     ][..];
     assert_eq!(
         "hello",
-        String::deserialize(&mut Deserializer { read: &mut read }).unwrap()
+        String::deserialize(&mut Deserializer::new(&mut read)).unwrap()
     );
 }
 
@@ -227,7 +1141,7 @@ fn test_string_seq() {
     ][..];
     assert_eq!(
         vec!["hello"; 5],
-        Vec::<String>::deserialize(&mut Deserializer { read: &mut read }).unwrap()
+        Vec::<String>::deserialize(&mut Deserializer::new(&mut read)).unwrap()
     )
 }
 
@@ -239,7 +1153,7 @@ fn test_some() {
     ][..];
     assert_eq!(
         Some(42),
-        Option::<u64>::deserialize(&mut Deserializer { read: &mut read }).unwrap()
+        Option::<u64>::deserialize(&mut Deserializer::new(&mut read)).unwrap()
     )
 }
 
@@ -248,7 +1162,7 @@ fn test_none() {
     let mut read: &[u8] = &[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00][..];
     assert_eq!(
         None,
-        Option::<u64>::deserialize(&mut Deserializer { read: &mut read }).unwrap()
+        Option::<u64>::deserialize(&mut Deserializer::new(&mut read)).unwrap()
     )
 }
 
@@ -260,7 +1174,7 @@ fn test_tuple() {
     ][..];
     assert_eq!(
         (1, 2, 3),
-        <(u64, u64, u64)>::deserialize(&mut Deserializer { read: &mut read }).unwrap()
+        <(u64, u64, u64)>::deserialize(&mut Deserializer::new(&mut read)).unwrap()
     )
 }
 
@@ -280,6 +1194,613 @@ fn test_struct() {
             name: String::from("hello"),
             num: 42
         },
-        Test::deserialize(&mut Deserializer { read: &mut read }).unwrap()
+        Test::deserialize(&mut Deserializer::new(&mut read)).unwrap()
     )
 }
+
+#[test]
+fn test_versioned() {
+    use crate::protocol::protocol_version_minor;
+    #[derive(Debug, PartialEq)]
+    struct Test {
+        name: String,
+        num: u64, // since 1.30
+    }
+    impl WorkerDecode for Test {
+        fn decode<R: std::io::Read>(de: &mut Deserializer<R>) -> crate::error::Result<Self> {
+            Ok(Test {
+                name: de.decode()?,
+                num: if protocol_version_minor(de.version()) >= 30 {
+                    de.decode()?
+                } else {
+                    0
+                },
+            })
+        }
+    }
+    let mut read: &[u8] = &[
+        0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, b'h', b'e', b'l', b'l', b'o', 0x00, 0x00,
+        0x00, 0x2a, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ][..];
+    assert_eq!(
+        Test {
+            name: String::from("hello"),
+            num: 0
+        },
+        Deserializer::with_version(&mut read, 1 << 8 | 29)
+            .decode::<Test>()
+            .unwrap()
+    );
+    assert_eq!(read.len(), 8);
+    let mut read: &[u8] = &[
+        0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, b'h', b'e', b'l', b'l', b'o', 0x00, 0x00,
+        0x00, 0x2a, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ][..];
+    assert_eq!(
+        Test {
+            name: String::from("hello"),
+            num: 42
+        },
+        Deserializer::with_version(&mut read, 1 << 8 | 30)
+            .decode::<Test>()
+            .unwrap()
+    );
+}
+
+#[test]
+fn test_derive() {
+    #[derive(WorkerDecode, Debug, PartialEq)]
+    enum Kind {
+        Foo,
+        Bar = 5,
+    }
+    #[derive(WorkerDecode, Debug, PartialEq)]
+    struct Test {
+        #[worker(store_path)]
+        path: String,
+        #[worker(empty_as_none)]
+        deriver: Option<String>,
+        kind: Kind,
+        #[worker(skip)]
+        id: u64,
+        #[worker(since = 30)]
+        num: u64,
+    }
+    let mut read: &[u8] = &[
+        0x0c, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, b'/', b'n', b'i', b'x', b'/', b's', b't',
+        b'o', b'r', b'e', b'/', b'a', 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ][..];
+    assert_eq!(
+        Test {
+            path: String::from("a"),
+            deriver: None,
+            kind: Kind::Bar,
+            id: 0,
+            num: 0,
+        },
+        Deserializer::with_version(&mut read, 1 << 8 | 29)
+            .decode::<Test>()
+            .unwrap()
+    );
+    let mut read: &[u8] = &[0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00][..];
+    assert!(Deserializer::new(&mut read).decode::<Kind>().is_err());
+}
+
+#[test]
+fn test_map() {
+    let mut read: &[u8] = &[
+        0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, b'a', 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, b'b', 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ][..];
+    assert_eq!(
+        std::collections::HashMap::from([(String::from("a"), 1), (String::from("b"), 2)]),
+        std::collections::HashMap::<String, u64>::deserialize(&mut Deserializer::new(&mut read))
+            .unwrap()
+    );
+}
+
+#[test]
+fn test_map_round_trip() {
+    use crate::types::{DrvOutput, DrvOutputs, Hash, Realisation, StorePath};
+    let id = |name: &str| DrvOutput {
+        drv_hash: Hash::from_digest(crate::hash::HashAlgo::Sha256, &[0xab; 32]).unwrap(),
+        output_name: name.to_string(),
+    };
+    let path = |name: &str| {
+        StorePath::from_base_name(&format!("00000000000000000000000000000000-{}", name)).unwrap()
+    };
+    let outputs: DrvOutputs = [
+        (
+            id("out"),
+            Realisation {
+                id: id("out"),
+                out_path: path("out"),
+                signature: vec![String::from("sig")],
+                dependent_realisations: [(id("dev"), path("dev")), (id("lib"), path("lib"))].into(),
+            },
+        ),
+        (
+            id("dev"),
+            Realisation {
+                id: id("dev"),
+                out_path: path("dev"),
+                signature: vec![],
+                dependent_realisations: Default::default(),
+            },
+        ),
+    ]
+    .into();
+    let mut buf = vec![];
+    crate::ser::Serializer::new(&mut buf)
+        .encode(&outputs)
+        .unwrap();
+    let mut read: &[u8] = &buf;
+    assert_eq!(
+        outputs,
+        Deserializer::new(&mut read).decode::<DrvOutputs>().unwrap()
+    );
+    assert!(read.is_empty());
+
+    let paths: std::collections::HashMap<String, StorePath> = [
+        (String::from("dev"), path("dev")),
+        (String::from("lib"), path("lib")),
+    ]
+    .into();
+    let mut buf = vec![];
+    crate::ser::Serializer::new(&mut buf)
+        .encode(&paths)
+        .unwrap();
+    let mut read: &[u8] = &buf;
+    assert_eq!(paths, Deserializer::new(&mut read).decode().unwrap());
+}
+
+#[test]
+fn test_enum() {
+    #[derive(Deserialize, Debug, PartialEq)]
+    enum Test {
+        Unit,
+        Newtype(u64),
+        Struct { name: String },
+    }
+    let mut read: &[u8] = &[
+        0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x2a, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, b'h', b'e', b'l', b'l', b'o', 0x00, 0x00, 0x00,
+    ][..];
+    assert_eq!(
+        vec![
+            Test::Unit,
+            Test::Newtype(42),
+            Test::Struct {
+                name: String::from("hello")
+            }
+        ],
+        Vec::<Test>::deserialize(&mut Deserializer::new(&mut read)).unwrap()
+    );
+    let mut read: &[u8] = &[
+        0x07, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, b'N', b'e', b'w', b't', b'y', b'p', b'e',
+        0x00, 0x2a, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ][..];
+    assert_eq!(
+        StringTagged(Test::Newtype(42)),
+        StringTagged::<Test>::deserialize(&mut Deserializer::new(&mut read)).unwrap()
+    );
+    // only an enum right inside the wrapper is tagged by name
+    let buf = [
+        0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+    assert_eq!(
+        StringTagged(vec![Test::Unit, Test::Unit]),
+        StringTagged::<Vec<Test>>::deserialize(&mut Deserializer::from_slice(&buf)).unwrap()
+    );
+    assert_eq!(
+        StringTagged((Test::Unit, Test::Unit)),
+        StringTagged::<(Test, Test)>::deserialize(&mut Deserializer::from_slice(&buf[8..]))
+            .unwrap()
+    );
+    let mut read: &[u8] = &[0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00][..];
+    assert!(Test::deserialize(&mut Deserializer::new(&mut read)).is_err());
+}
+
+#[test]
+fn test_errors() {
+    #[derive(Deserialize, Debug)]
+    #[allow(dead_code)]
+    struct Inner {
+        references: Vec<String>,
+    }
+    #[derive(Deserialize, Debug)]
+    #[allow(dead_code)]
+    struct Test {
+        num: u64,
+        inner: Inner,
+    }
+    let mut read: &[u8] = &[
+        0x2a, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, b'a', 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00,
+    ][..];
+    let err = Test::deserialize(&mut Deserializer::new(&mut read)).unwrap_err();
+    assert!(matches!(err.root(), Error::FromUtf8(_)));
+    assert_eq!(
+        err.to_string(),
+        format!(
+            "Test.inner.references[1]: {} at offset 32",
+            String::from_utf8(vec![0xff]).unwrap_err()
+        )
+    );
+
+    // borrowed strings fail without a copy
+    let buf = [
+        0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00,
+    ];
+    let err = <&str>::deserialize(&mut Deserializer::from_slice(&buf)).unwrap_err();
+    assert!(matches!(err.root(), Error::Utf8(_)));
+
+    let mut read: &[u8] = &[0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00][..];
+    let err = u8::deserialize(&mut Deserializer::new(&mut read)).unwrap_err();
+    assert_eq!(err.to_string(), "256 is out of range for u8");
+
+    let mut read: &[u8] = &[0x2a, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00][..];
+    assert_eq!(
+        42,
+        i32::deserialize(&mut Deserializer::new(&mut read)).unwrap()
+    );
+
+    let mut read: &[u8] = &[0x2a, 0x00, 0x00, 0x00][..];
+    let err = Test::deserialize(&mut Deserializer::new(&mut read)).unwrap_err();
+    assert!(matches!(err.root(), Error::IO(_)));
+    assert!(err.to_string().starts_with("Test.num: "));
+
+    let mut read: &[u8] = &[][..];
+    assert!(matches!(
+        f64::deserialize(&mut Deserializer::new(&mut read)),
+        Err(Error::Unsupported(_))
+    ));
+}
+
+#[test]
+fn test_limits() {
+    let limits = Limits {
+        max_string_len: 4,
+        max_seq_len: 2,
+        max_total_bytes: 32,
+    };
+    let mut read: &[u8] = &[
+        0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, b'h', b'e', b'l', b'l', b'o', 0x00, 0x00,
+        0x00,
+    ][..];
+    let err = String::deserialize(&mut Deserializer::new(&mut read).with_limits(limits));
+    assert!(matches!(
+        err,
+        Err(Error::LimitExceeded {
+            what: "string length",
+            value: 5,
+            limit: 4
+        })
+    ));
+
+    let mut read: &[u8] = &[0xff; 8][..];
+    let err = Vec::<u64>::deserialize(&mut Deserializer::new(&mut read).with_limits(limits));
+    assert!(matches!(
+        err,
+        Err(Error::LimitExceeded {
+            what: "sequence length",
+            ..
+        })
+    ));
+    let mut read: &[u8] = &[0xff; 8][..];
+    let err = Deserializer::new(&mut read)
+        .with_limits(limits)
+        .decode::<Vec<u64>>();
+    assert!(matches!(err, Err(Error::LimitExceeded { .. })));
+
+    let mut read: &[u8] = &[0x01; 40][..];
+    let mut des = Deserializer::new(&mut read).with_limits(limits);
+    for _ in 0..4 {
+        u64::deserialize(&mut des).unwrap();
+    }
+    let err = u64::deserialize(&mut des).unwrap_err();
+    assert_eq!(err.to_string(), "total bytes 40 exceeds the limit of 32");
+}
+
+#[test]
+fn test_borrowed() {
+    #[derive(Deserialize, Debug, PartialEq)]
+    struct Test<'a> {
+        name: &'a str,
+        #[serde(borrow)]
+        paths: Vec<&'a str>,
+        data: &'a [u8],
+    }
+    let buf = [
+        0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, b'h', b'e', b'l', b'l', b'o', 0x00, 0x00,
+        0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, b'a', 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, b'b', 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x01, 0x02, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x2a, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+    let mut des = Deserializer::from_slice(&buf);
+    let test = Test::deserialize(&mut des).unwrap();
+    assert_eq!(
+        test,
+        Test {
+            name: "hello",
+            paths: vec!["a", "b"],
+            data: &[1, 2, 3],
+        }
+    );
+    assert_eq!(test.name.as_ptr(), buf[8..].as_ptr());
+    assert_eq!(des.remaining().len(), 8);
+    assert_eq!(42, u64::deserialize(&mut des).unwrap());
+    assert!(des.remaining().is_empty());
+
+    // borrowing is only possible from a slice
+    let mut read: &[u8] = &buf[..];
+    assert!(Test::deserialize(&mut Deserializer::new(&mut read)).is_err());
+    // but owned values work in both modes
+    assert_eq!(
+        "hello",
+        String::deserialize(&mut Deserializer::from_slice(&buf)).unwrap()
+    );
+    let err = <&str>::deserialize(&mut Deserializer::from_slice(&buf[..12])).unwrap_err();
+    assert!(matches!(err.root(), Error::IO(_)));
+}
+
+#[test]
+fn test_reader() {
+    use std::io::Read;
+    let data: Vec<u8> = (0..1_000_003_u32).map(|x| x as u8).collect();
+    let mut buf = vec![];
+    let mut ser = crate::ser::Serializer::new(&mut buf);
+    ser.serialize_reader(data.len() as u64, &data[..]).unwrap();
+    ser.encode(&42_u64).unwrap();
+
+    let mut read: &[u8] = &buf;
+    let mut des = Deserializer::new(&mut read).with_limits(Limits {
+        max_string_len: 1024,
+        ..Limits::UNLIMITED
+    });
+    let mut reader = des.deserialize_reader().unwrap();
+    assert_eq!(reader.remaining(), data.len() as u64);
+    let mut out = vec![];
+    reader.read_to_end(&mut out).unwrap();
+    assert_eq!(out, data);
+    assert_eq!(42, u64::deserialize(&mut des).unwrap());
+    assert_eq!(des.offset(), buf.len() as u64);
+
+    let mut des = Deserializer::from_slice(&buf);
+    des.deserialize_reader().unwrap().finish().unwrap();
+    assert_eq!(42, u64::deserialize(&mut des).unwrap());
+
+    // stopping at the end of the payload leaves nothing of it in the stream,
+    // and the payload does not count against the total
+    let mut des = Deserializer::from_slice(&buf).with_limits(Limits {
+        max_total_bytes: 16,
+        ..Limits::UNLIMITED
+    });
+    let mut out = vec![0; data.len()];
+    des.deserialize_reader()
+        .unwrap()
+        .read_exact(&mut out)
+        .unwrap();
+    assert_eq!(out, data);
+    assert_eq!(42, u64::deserialize(&mut des).unwrap());
+    assert!(matches!(
+        u64::deserialize(&mut des).unwrap_err().root(),
+        Error::LimitExceeded { .. }
+    ));
+
+    let mut read: &[u8] = &[
+        0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, b'h', b'e', b'l', b'l', b'o', 0x00, 0x01,
+        0x00,
+    ][..];
+    let mut des = Deserializer::new(&mut read);
+    let mut reader = des.deserialize_reader().unwrap();
+    let mut out = [0; 5];
+    reader.read_exact(&mut out).unwrap();
+    assert_eq!(&out, b"hello");
+    let err = reader.read(&mut out).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn test_async() {
+    let mut read: &[u8] = &[
+        0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, b'h', b'e', b'l', b'l', b'o', 0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, b'h', b'e', b'l', b'l', b'o', 0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, b'h', b'e', b'l', b'l', b'o', 0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, b'h', b'e', b'l', b'l', b'o', 0x00, 0x00, 0x00, 0x05, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, b'h', b'e', b'l', b'l', b'o', 0x00, 0x00, 0x00, 0x2a, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ][..];
+    let mut des = AsyncDeserializer::new(&mut read);
+    assert_eq!(vec!["hello"; 5], des.decode::<Vec<String>>().await.unwrap());
+    assert_eq!(42, des.read_u64().await.unwrap());
+    assert!(read.is_empty());
+
+    let mut read: &[u8] = &[
+        0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, b'h', b'e', b'l', b'l', b'o', 0x00, 0x00,
+        0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x2a, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00,
+    ][..];
+    let mut des = AsyncDeserializer::new(&mut read);
+    assert_eq!("hello", des.read_string().await.unwrap());
+    assert_eq!(Some(42), des.decode::<Option<u64>>().await.unwrap());
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn test_async_chunks() {
+    /// hands out one byte every other read, telling the task to wait in between
+    struct Trickle<'a> {
+        data: &'a [u8],
+        wait: bool,
+    }
+    impl<'a> tokio::io::AsyncRead for Trickle<'a> {
+        fn poll_read(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+            buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            self.wait = !self.wait;
+            if self.wait {
+                cx.waker().wake_by_ref();
+                return std::task::Poll::Pending;
+            }
+            if let Some((first, rest)) = self.data.split_first() {
+                buf.put_slice(&[*first]);
+                self.data = rest;
+            }
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+    use crate::store_path::StorePath;
+    use crate::types::{UnkeyedValidPathInfo, ValidPathInfo};
+    let value: Vec<String> = (0..200).map(|x| x.to_string()).collect();
+    let info = ValidPathInfo {
+        path: StorePath::from_base_name("g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo").unwrap(),
+        info: UnkeyedValidPathInfo {
+            deriver: Some(
+                StorePath::from_base_name("g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-bar.drv").unwrap(),
+            ),
+            hash: crate::hash::Hash::sha256(b"foo"),
+            references: vec![
+                StorePath::from_base_name("g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo").unwrap(),
+            ],
+            registration_time: 23423,
+            nar_size: 34878,
+            ultimate: true,
+            sigs: vec![String::from("fake-sig-1")],
+            ca: None,
+        },
+    };
+    let mut data = vec![];
+    crate::ser::Serializer::new(&mut data)
+        .encode(&value)
+        .unwrap();
+    let value_len = data.len();
+    let mut ser = crate::ser::Serializer::new(&mut data);
+    ser.encode(&info).unwrap();
+    ser.encode(&42_u64).unwrap();
+    let mut read = Trickle {
+        data: &data,
+        wait: false,
+    };
+    let mut des = AsyncDeserializer::new(&mut read);
+    assert_eq!(des.decode::<Vec<String>>().await.unwrap(), value);
+    assert_eq!(des.decode::<ValidPathInfo>().await.unwrap(), info);
+    assert_eq!(42, des.read_u64().await.unwrap());
+    assert_eq!(des.offset(), data.len() as u64);
+
+    let mut read = &data[..data.len() - 1];
+    let err = AsyncDeserializer::new(&mut read)
+        .decode::<(Vec<String>, (ValidPathInfo, u64))>()
+        .await
+        .unwrap_err();
+    assert!(matches!(err.root(), Error::IO(_)));
+
+    // cut short in the middle of the deriver
+    let cut = value_len + 56 + 20;
+    let mut read = &data[..cut];
+    let err = AsyncDeserializer::new(&mut read)
+        .decode::<(Vec<String>, ValidPathInfo)>()
+        .await
+        .unwrap_err();
+    let sync_err = Deserializer::new(&mut &data[..cut])
+        .decode::<(Vec<String>, ValidPathInfo)>()
+        .unwrap_err();
+    match (err, sync_err) {
+        (
+            Error::Decode { path, offset, .. },
+            Error::Decode {
+                path: sync_path,
+                offset: sync_offset,
+                ..
+            },
+        ) => {
+            assert_eq!(path, "ValidPathInfo.info.deriver");
+            assert_eq!((path, offset), (sync_path, sync_offset));
+        }
+        errs => panic!("{:?}", errs),
+    }
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn test_async_limits() {
+    let mut data = vec![];
+    let mut ser = crate::ser::Serializer::new(&mut data);
+    ser.encode("hello").unwrap();
+    ser.encode("world").unwrap();
+    let limits = Limits {
+        max_total_bytes: 24,
+        ..Limits::UNLIMITED
+    };
+    // each value fits, but both do not
+    let mut read = &data[..];
+    let mut des = AsyncDeserializer::new(&mut read).with_limits(limits);
+    assert_eq!("hello", des.read_string().await.unwrap());
+    let err = des.read_string().await.unwrap_err();
+    assert!(matches!(
+        err,
+        Error::LimitExceeded {
+            what: "total bytes",
+            ..
+        }
+    ));
+    // checked before the string is read
+    assert_eq!(read.len(), 8);
+
+    let limits = Limits {
+        max_string_len: 4,
+        ..Limits::UNLIMITED
+    };
+    let mut read = &data[..];
+    let err = AsyncDeserializer::new(&mut read)
+        .with_limits(limits)
+        .read_string()
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err,
+        Error::LimitExceeded {
+            what: "string length",
+            ..
+        }
+    ));
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn test_async_framed() {
+    use std::io::Write;
+    use tokio::io::AsyncReadExt;
+    let data: Vec<u8> = (0..100_000_u32).map(|x| x as u8).collect();
+    let mut buf = vec![];
+    let mut fw = crate::ser::FramedWriter::with_frame_size(&mut buf, 4093);
+    fw.write_all(&data).unwrap();
+    fw.finish().unwrap();
+    buf.extend_from_slice(&[0x2a, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+    let mut read: &[u8] = &buf;
+    let mut out = vec![];
+    AsyncFramedReader::new(&mut read)
+        .read_to_end(&mut out)
+        .await
+        .unwrap();
+    assert_eq!(out, data);
+    assert_eq!(
+        42,
+        AsyncDeserializer::new(&mut read).read_u64().await.unwrap()
+    );
+}
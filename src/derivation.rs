@@ -0,0 +1,550 @@
+//! derivations as stored in `.drv` files, in ATerm format
+//!
+//! adapated from <https://github.com/NixOS/nix/blob/master/src/libstore/derivations.cc>
+
+use crate::content_address::ContentAddress;
+use crate::error::{Error, Result};
+use crate::hash::{Hash, HashFormat};
+use crate::store_path::FileIngestionMethod;
+use crate::types::{BasicDerivation, DerivationOutput, StorePath};
+
+/// a derivation with its input derivations, fields keep the order of the
+/// `.drv` file so that printing gives back the same bytes
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Derivation {
+    /// the derivation name, the `.drv` store path without the suffix
+    pub name: String,
+    pub outputs: Vec<DerivationOutput>,
+    /// input derivations and which of their outputs are used
+    pub input_drvs: Vec<(StorePath, Vec<String>)>,
+    pub input_srcs: Vec<StorePath>,
+    pub platform: String,
+    pub builder: String,
+    pub args: Vec<String>,
+    pub env: Vec<(String, String)>,
+}
+
+impl Derivation {
+    /// parse the contents of a `.drv` file
+    pub fn from_aterm(name: &str, s: &str) -> Result<Self> {
+        let mut p = Parser {
+            s: s.as_bytes(),
+            pos: 0,
+        };
+        p.expect(b"Derive(")?;
+        let outputs = p.list(|p| {
+            p.expect(b"(")?;
+            let name = p.string()?;
+            p.expect(b",")?;
+            let path_s = p.string()?;
+            p.expect(b",")?;
+            let hash_algo = p.string()?;
+            p.expect(b",")?;
+            let hash = p.string()?;
+            p.expect(b")")?;
+            Ok(DerivationOutput {
+                name,
+                path_s,
+                hash_algo,
+                hash,
+            })
+        })?;
+        p.expect(b",")?;
+        let input_drvs = p.list(|p| {
+            p.expect(b"(")?;
+            let path = p.store_path()?;
+            p.expect(b",")?;
+            let outputs = p.list(Parser::string)?;
+            p.expect(b")")?;
+            Ok((path, outputs))
+        })?;
+        p.expect(b",")?;
+        let input_srcs = p.list(Parser::store_path)?;
+        p.expect(b",")?;
+        let platform = p.string()?;
+        p.expect(b",")?;
+        let builder = p.string()?;
+        p.expect(b",")?;
+        let args = p.list(Parser::string)?;
+        p.expect(b",")?;
+        let env = p.list(|p| {
+            p.expect(b"(")?;
+            let key = p.string()?;
+            p.expect(b",")?;
+            let value = p.string()?;
+            p.expect(b")")?;
+            Ok((key, value))
+        })?;
+        p.expect(b")")?;
+        if p.pos != p.s.len() {
+            return Err(p.error("trailing data"));
+        }
+        Ok(Derivation {
+            name: name.to_string(),
+            outputs,
+            input_drvs,
+            input_srcs,
+            platform,
+            builder,
+            args,
+            env,
+        })
+    }
+
+    /// render in ATerm format, as written to the `.drv` file
+    pub fn to_aterm(&self) -> String {
+        let input_drvs: Vec<(String, Vec<String>)> = self
+            .input_drvs
+            .iter()
+            .map(|(path, outputs)| (path.to_string(), outputs.clone()))
+            .collect();
+        self.aterm(&input_drvs)
+    }
+
+    /// ATerm with the given input derivations, which `hash_modulo` replaces
+    /// by their hashes
+    fn aterm(&self, input_drvs: &[(String, Vec<String>)]) -> String {
+        let mut s = String::from("Derive(");
+        write_list(&mut s, &self.outputs, |s, x| {
+            s.push('(');
+            write_string(s, &x.name);
+            s.push(',');
+            write_string(s, &x.path_s);
+            s.push(',');
+            write_string(s, &x.hash_algo);
+            s.push(',');
+            write_string(s, &x.hash);
+            s.push(')');
+        });
+        s.push(',');
+        write_list(&mut s, input_drvs, |s, (path, outputs)| {
+            s.push('(');
+            write_string(s, path);
+            s.push(',');
+            write_list(s, outputs, |s, x| write_string(s, x));
+            s.push(')');
+        });
+        s.push(',');
+        write_list(&mut s, &self.input_srcs, |s, x| {
+            write_string(s, &x.to_string())
+        });
+        s.push(',');
+        write_string(&mut s, &self.platform);
+        s.push(',');
+        write_string(&mut s, &self.builder);
+        s.push(',');
+        write_list(&mut s, &self.args, |s, x| write_string(s, x));
+        s.push(',');
+        write_list(&mut s, &self.env, |s, (key, value)| {
+            s.push('(');
+            write_string(s, key);
+            s.push(',');
+            write_string(s, value);
+            s.push(')');
+        });
+        s.push(')');
+        s
+    }
+
+    /// the store path of the `.drv` file, which refers to the input
+    /// derivations and sources
+    pub fn store_path(&self) -> Result<StorePath> {
+        let mut references = self.input_srcs.clone();
+        references.extend(self.input_drvs.iter().map(|x| x.0.clone()));
+        StorePath::make_text(
+            &format!("{}.drv", self.name),
+            &Hash::sha256(self.to_aterm().as_bytes()),
+            &references,
+        )
+    }
+
+    /// the output and its content address if this is a fixed-output
+    /// derivation, which has the single output `out` with a known hash
+    pub fn fixed_output(&self) -> Result<Option<(&DerivationOutput, ContentAddress)>> {
+        match &self.outputs[..] {
+            [out] if out.name == "out" && !out.hash.is_empty() => {
+                Ok(out.content_address()?.map(|ca| (out, ca)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// `hashDerivationModulo`, fixed-output derivations hash to their output
+    /// so that other ways to fetch the same contents do not change the hash
+    /// of derivations depending on them, `input_hash` gives the hash modulo
+    /// of an input derivation
+    pub fn hash_modulo<F>(&self, mut input_hash: F) -> Result<Hash>
+    where
+        F: FnMut(&StorePath) -> Result<Hash>,
+    {
+        if let Some((out, _)) = self.fixed_output()? {
+            return Ok(Hash::sha256(
+                format!("fixed:out:{}:{}:{}", out.hash_algo, out.hash, out.path_s).as_bytes(),
+            ));
+        }
+        let mut input_drvs = vec![];
+        for (path, outputs) in &self.input_drvs {
+            let hash = input_hash(path)?.encode(HashFormat::Base16);
+            input_drvs.push((hash, outputs.clone()));
+        }
+        input_drvs.sort();
+        Ok(Hash::sha256(self.aterm(&input_drvs).as_bytes()))
+    }
+
+    /// the hash that addresses the outputs and identifies them in
+    /// realisations, the hash modulo with the output paths left out
+    pub fn output_hash<F>(&self, input_hash: F) -> Result<Hash>
+    where
+        F: FnMut(&StorePath) -> Result<Hash>,
+    {
+        if self.fixed_output()?.is_some() {
+            return self.hash_modulo(input_hash);
+        }
+        if self.outputs.iter().any(|x| !x.hash_algo.is_empty()) {
+            return Err(Error::Unsupported("content addressed derivation"));
+        }
+        // the output paths are not known when hashing, so they are left
+        // empty, as is the environment variable of each output
+        let mut masked = self.clone();
+        for out in &mut masked.outputs {
+            out.path_s.clear();
+        }
+        for (key, value) in &mut masked.env {
+            if self.outputs.iter().any(|x| &x.name == key) {
+                value.clear();
+            }
+        }
+        masked.hash_modulo(input_hash)
+    }
+
+    /// the store paths the outputs must have, by output name
+    pub fn output_paths<F>(&self, input_hash: F) -> Result<Vec<(String, StorePath)>>
+    where
+        F: FnMut(&StorePath) -> Result<Hash>,
+    {
+        if let Some((out, ca)) = self.fixed_output()? {
+            return Ok(vec![(
+                out.name.clone(),
+                ca.store_path(&self.name, &[], false)?,
+            )]);
+        }
+        let hash = self.output_hash(input_hash)?;
+        self.outputs
+            .iter()
+            .map(|x| {
+                Ok((
+                    x.name.clone(),
+                    StorePath::make_output(&x.name, &hash, &self.name)?,
+                ))
+            })
+            .collect()
+    }
+
+    /// the derivation as sent with BuildDerivation, `resolve` gives the store
+    /// path of an output of an input derivation, these become input sources
+    pub fn to_basic<F>(&self, drv_path: &StorePath, mut resolve: F) -> Result<BasicDerivation>
+    where
+        F: FnMut(&StorePath, &str) -> Result<StorePath>,
+    {
+        let mut input_srcs = self.input_srcs.clone();
+        for (drv, outputs) in &self.input_drvs {
+            for output in outputs {
+                input_srcs.push(resolve(drv, output)?);
+            }
+        }
+        input_srcs.sort();
+        input_srcs.dedup();
+        Ok(BasicDerivation {
+            name: drv_path.to_string(),
+            outputs: self.outputs.clone(),
+            input_srcs,
+            platform: self.platform.clone(),
+            builder: self.builder.clone(),
+            args: self.args.clone(),
+            env: self.env.clone(),
+        })
+    }
+}
+
+impl DerivationOutput {
+    /// the content address of a fixed output, `hash_algo` is like `r:sha256`
+    /// and `hash` is base16
+    pub fn content_address(&self) -> Result<Option<ContentAddress>> {
+        if self.hash_algo.is_empty() {
+            return Ok(None);
+        }
+        if self.hash.is_empty() {
+            return Err(Error::Unsupported("content addressed derivation"));
+        }
+        let (text, algo) = match self.hash_algo.strip_prefix("text:") {
+            Some(algo) => (true, algo),
+            None => (false, &self.hash_algo[..]),
+        };
+        let (method, algo) = match algo.strip_prefix("r:") {
+            Some(algo) => (FileIngestionMethod::Recursive, algo),
+            None => (FileIngestionMethod::Flat, algo),
+        };
+        let hash = Hash::parse_any(&self.hash, Some(algo.parse()?))?;
+        Ok(Some(if text {
+            ContentAddress::Text(hash)
+        } else {
+            ContentAddress::Fixed { method, hash }
+        }))
+    }
+}
+
+struct Parser<'a> {
+    s: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, reason: &'static str) -> Error {
+        Error::InvalidDerivation {
+            offset: self.pos,
+            reason,
+        }
+    }
+    fn expect(&mut self, token: &[u8]) -> Result<()> {
+        if !self.s[self.pos..].starts_with(token) {
+            return Err(self.error("unexpected token"));
+        }
+        self.pos += token.len();
+        Ok(())
+    }
+    fn string(&mut self) -> Result<String> {
+        self.expect(b"\"")?;
+        let mut out = vec![];
+        loop {
+            match self.s.get(self.pos) {
+                None => return Err(self.error("unterminated string")),
+                Some(b'"') => break,
+                Some(b'\\') => {
+                    self.pos += 1;
+                    out.push(match self.s.get(self.pos) {
+                        None => return Err(self.error("unterminated string")),
+                        Some(b'n') => b'\n',
+                        Some(b'r') => b'\r',
+                        Some(b't') => b'\t',
+                        Some(&c) => c,
+                    });
+                }
+                Some(&c) => out.push(c),
+            }
+            self.pos += 1;
+        }
+        self.pos += 1;
+        Ok(String::from_utf8(out)?)
+    }
+    fn store_path(&mut self) -> Result<StorePath> {
+        self.string()?.parse()
+    }
+    fn list<T>(&mut self, mut item: impl FnMut(&mut Self) -> Result<T>) -> Result<Vec<T>> {
+        self.expect(b"[")?;
+        let mut items = vec![];
+        if self.expect(b"]").is_ok() {
+            return Ok(items);
+        }
+        loop {
+            items.push(item(self)?);
+            if self.expect(b"]").is_ok() {
+                return Ok(items);
+            }
+            self.expect(b",")?;
+        }
+    }
+}
+
+fn write_string(s: &mut String, x: &str) {
+    s.push('"');
+    for c in x.chars() {
+        match c {
+            '"' => s.push_str("\\\""),
+            '\\' => s.push_str("\\\\"),
+            '\n' => s.push_str("\\n"),
+            '\r' => s.push_str("\\r"),
+            '\t' => s.push_str("\\t"),
+            c => s.push(c),
+        }
+    }
+    s.push('"');
+}
+
+fn write_list<T>(s: &mut String, items: &[T], mut item: impl FnMut(&mut String, &T)) {
+    s.push('[');
+    for (i, x) in items.iter().enumerate() {
+        if i > 0 {
+            s.push(',');
+        }
+        item(s, x);
+    }
+    s.push(']');
+}
+
+#[cfg(test)]
+const HELLO_DRV: &str = r#"Derive([("out","/nix/store/1q8w6gl1ll0mwfkqc3c2yx005s6wwfrl-hello-2.12.1","","")],[("/nix/store/0hnc2ibfvrjkm2ccq6fhfnlvmp2yv6hx-bash-5.2-p15.drv",["out"]),("/nix/store/4i3v6fkvbv8ix7x5ra9b3kbzkvanmfjd-stdenv-linux.drv",["out"]),("/nix/store/pqs0c1xcw3iyyhh1c5ihx8dkxp1lhzwd-hello-2.12.1.tar.gz.drv",["out"])],["/nix/store/6xg259477c90a229xwmb53pdfkn6ig3g-default-builder.sh"],"x86_64-linux","/nix/store/pbfraw351mksnkp2ni9c4rkc9cpp89iv-bash-5.2-p15/bin/bash",["-e","/nix/store/6xg259477c90a229xwmb53pdfkn6ig3g-default-builder.sh"],[("builder","/nix/store/pbfraw351mksnkp2ni9c4rkc9cpp89iv-bash-5.2-p15/bin/bash"),("name","hello-2.12.1"),("out","/nix/store/1q8w6gl1ll0mwfkqc3c2yx005s6wwfrl-hello-2.12.1"),("preInstall","echo \"say \\\"hi\\\"\"\n\tdone\r"),("src","/nix/store/pa10z4ngm0g83kx9mssrqzz30s84vq7k-hello-2.12.1.tar.gz"),("stdenv","/nix/store/kmfaajdpyyyg319vfqni5jm9wkxjmf73-stdenv-linux"),("system","x86_64-linux")])"#;
+
+#[test]
+fn test_aterm() {
+    let drv = Derivation::from_aterm("hello-2.12.1", HELLO_DRV).unwrap();
+    assert_eq!(drv.to_aterm(), HELLO_DRV);
+    assert_eq!(drv.outputs[0].name, "out");
+    assert_eq!(drv.input_drvs.len(), 3);
+    assert_eq!(drv.input_drvs[2].1, ["out"]);
+    assert_eq!(drv.args[0], "-e");
+    assert_eq!(drv.env[3].1, "echo \"say \\\"hi\\\"\"\n\tdone\r");
+
+    for (s, offset, reason) in [
+        ("Derive(", 7, "unexpected token"),
+        ("Derive([],[],[],\"x86_64-linux", 29, "unterminated string"),
+        ("Derive([],[],[],\"\",\"\",[],[])x", 28, "trailing data"),
+        (
+            "Derive([(\"out\")],[],[],\"\",\"\",[],[])",
+            14,
+            "unexpected token",
+        ),
+        (
+            "Derive([],[],[],\"\",\"\",[\"\",],[])",
+            26,
+            "unexpected token",
+        ),
+        ("Derive([],[],[],\"\",\"\",[\"\\", 25, "unterminated string"),
+    ] {
+        match Derivation::from_aterm("x", s) {
+            Err(Error::InvalidDerivation {
+                offset: o,
+                reason: r,
+            }) => {
+                assert_eq!((o, r), (offset, reason), "{}", s)
+            }
+            res => panic!("{}: {:?}", s, res),
+        }
+    }
+    assert!(matches!(
+        Derivation::from_aterm("x", "Derive([],[],[\"/tmp/x\"],\"\",\"\",[],[])"),
+        Err(Error::InvalidStorePath { .. })
+    ));
+}
+
+#[cfg(test)]
+pub(crate) const BAR_DRV: &str = r#"Derive([("out","/nix/store/4q0pg5zpfmznxscq3avycvf9xdvx50n3-bar","r:sha256","08813cbee9903c62be4c5027726a418a300da4500b2d369d3af9286f4815ceba")],[],[],":",":",[],[("builder",":"),("name","bar"),("out","/nix/store/4q0pg5zpfmznxscq3avycvf9xdvx50n3-bar"),("outputHash","08813cbee9903c62be4c5027726a418a300da4500b2d369d3af9286f4815ceba"),("outputHashAlgo","sha256"),("outputHashMode","recursive"),("system",":")])"#;
+
+#[cfg(test)]
+pub(crate) const FOO_DRV: &str = r#"Derive([("out","/nix/store/5vyvcwah9l9kf07d52rcgdk70g2f4y13-foo","","")],[("/nix/store/0hm2f1psjpcwg8fijsmr4wwxrx59s092-bar.drv",["out"])],[],":",":",[],[("bar","/nix/store/4q0pg5zpfmznxscq3avycvf9xdvx50n3-bar"),("builder",":"),("name","foo"),("out","/nix/store/5vyvcwah9l9kf07d52rcgdk70g2f4y13-foo"),("system",":")])"#;
+
+#[test]
+fn test_hash_modulo() {
+    let bar = Derivation::from_aterm("bar", BAR_DRV).unwrap();
+    let foo = Derivation::from_aterm("foo", FOO_DRV).unwrap();
+    let bar_path = bar.store_path().unwrap();
+    assert_eq!(
+        bar_path.to_string(),
+        "/nix/store/0hm2f1psjpcwg8fijsmr4wwxrx59s092-bar.drv"
+    );
+    assert_eq!(
+        foo.store_path().unwrap().to_string(),
+        "/nix/store/4wvvbi4jwn0prsdxb7vs673qa5h9gr7x-foo.drv"
+    );
+
+    let no_inputs = |_: &StorePath| -> Result<Hash> { unreachable!() };
+    let bar_hash = bar.hash_modulo(no_inputs).unwrap();
+    let paths = bar.output_paths(no_inputs).unwrap();
+    assert_eq!(paths[0].0, "out");
+    assert_eq!(paths[0].1.to_string(), bar.outputs[0].path_s);
+
+    let inputs = |path: &StorePath| {
+        assert_eq!(path, &bar_path);
+        Ok(bar_hash.clone())
+    };
+    let paths = foo.output_paths(inputs).unwrap();
+    assert_eq!(paths[0].1.to_string(), foo.outputs[0].path_s);
+
+    // changing how bar is fetched does not change foo
+    let mut bar2 = bar.clone();
+    bar2.builder = String::from("/bin/sh");
+    assert_eq!(bar2.hash_modulo(no_inputs).unwrap(), bar_hash);
+    assert_ne!(bar2.store_path().unwrap(), bar_path);
+
+    let mut foo2 = foo.clone();
+    foo2.args.push(String::from("-e"));
+    assert_ne!(foo2.output_paths(inputs).unwrap(), paths);
+
+    let mut floating = foo.clone();
+    floating.outputs[0].hash_algo = String::from("r:sha256");
+    assert!(matches!(
+        floating.output_paths(inputs),
+        Err(Error::Unsupported(_))
+    ));
+}
+
+#[test]
+fn test_to_basic() {
+    let drv = Derivation::from_aterm("hello-2.12.1", HELLO_DRV).unwrap();
+    let drv_path: StorePath = "/nix/store/6y7dz0y0yfzd0skfai6bwqqihpvhfmcj-hello-2.12.1.drv"
+        .parse()
+        .unwrap();
+    let basic = drv
+        .to_basic(&drv_path, |drv, output| {
+            assert_eq!(output, "out");
+            let name = drv.name().strip_suffix(".drv").unwrap();
+            StorePath::from_base_name(&format!("{}-{}", "0".repeat(32), name))
+        })
+        .unwrap();
+    assert_eq!(basic.name, drv_path.to_string());
+    assert_eq!(basic.outputs, drv.outputs);
+    let srcs: Vec<String> = basic.input_srcs.iter().map(|x| x.to_string()).collect();
+    assert_eq!(
+        srcs,
+        [
+            "/nix/store/00000000000000000000000000000000-bash-5.2-p15",
+            "/nix/store/00000000000000000000000000000000-hello-2.12.1.tar.gz",
+            "/nix/store/00000000000000000000000000000000-stdenv-linux",
+            "/nix/store/6xg259477c90a229xwmb53pdfkn6ig3g-default-builder.sh",
+        ]
+    );
+    assert_eq!(basic.env, drv.env);
+
+    let err = drv.to_basic(&drv_path, |_, _| Err(Error::NotImplemented));
+    assert!(matches!(err, Err(Error::NotImplemented)));
+}
+
+#[cfg(test)]
+use proptest::prelude::*;
+
+#[cfg(test)]
+proptest! {
+    #[test]
+    fn test_aterm_round_trip(
+        outputs in proptest::collection::vec(any::<[String; 4]>(), 0..4),
+        input_drvs in proptest::collection::vec(
+            (crate::types::arb_store_path(), proptest::collection::vec(any::<String>(), 0..3)),
+            0..4,
+        ),
+        input_srcs in proptest::collection::vec(crate::types::arb_store_path(), 0..4),
+        strings in any::<[String; 2]>(),
+        args in proptest::collection::vec(any::<String>(), 0..4),
+        env in proptest::collection::vec(any::<(String, String)>(), 0..4),
+    ) {
+        let [platform, builder] = strings;
+        let drv = Derivation {
+            name: String::from("foo"),
+            outputs: outputs
+                .into_iter()
+                .map(|[name, path_s, hash_algo, hash]| DerivationOutput {
+                    name,
+                    path_s,
+                    hash_algo,
+                    hash,
+                })
+                .collect(),
+            input_drvs,
+            input_srcs,
+            platform,
+            builder,
+            args,
+            env,
+        };
+        let aterm = drv.to_aterm();
+        prop_assert_eq!(&Derivation::from_aterm("foo", &aterm).unwrap(), &drv);
+    }
+}
@@ -0,0 +1,436 @@
+//! paths to build or fetch, either a store path or outputs of a derivation
+//!
+//! adapated from <https://github.com/NixOS/nix/blob/master/src/libstore/derived-path.cc>
+//! and <https://github.com/NixOS/nix/blob/master/src/libstore/path-with-outputs.cc>
+
+use crate::de::WorkerDecode;
+use crate::error::{Error, Result};
+use crate::protocol::protocol_version_minor;
+use crate::ser::WorkerEncode;
+use crate::store_path::StorePath;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// output names have the characters of store path names
+fn valid_output_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|x| x.is_ascii_alphanumeric() || b"+-._?=".contains(&x))
+}
+
+/// the outputs of a derivation that are wanted, `*` or `out,dev`
+#[derive(Clone, Debug, PartialEq, Eq, std::hash::Hash)]
+pub enum OutputsSpec {
+    All,
+    Names(BTreeSet<String>),
+}
+
+impl std::str::FromStr for OutputsSpec {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        if s == "*" {
+            return Ok(OutputsSpec::All);
+        }
+        let names: BTreeSet<String> = s.split(',').map(String::from).collect();
+        if !names.iter().all(|x| valid_output_name(x)) {
+            return Err(Error::InvalidDerivedPath {
+                path: s.to_string(),
+                reason: "invalid output name",
+            });
+        }
+        Ok(OutputsSpec::Names(names))
+    }
+}
+
+impl std::fmt::Display for OutputsSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            OutputsSpec::All => f.write_str("*"),
+            OutputsSpec::Names(names) => {
+                f.write_str(&names.iter().cloned().collect::<Vec<_>>().join(","))
+            }
+        }
+    }
+}
+
+/// a store path, or outputs of a derivation that may still need building
+///
+/// rendered as `{drv}^{outputs}`, or `{drv}!{outputs}` in the legacy syntax
+/// that is also used on the wire
+#[derive(Clone, Debug, PartialEq, Eq, std::hash::Hash)]
+pub enum DerivedPath {
+    Opaque(StorePath),
+    Built {
+        drv_path: StorePath,
+        outputs: OutputsSpec,
+    },
+}
+
+impl DerivedPath {
+    /// parse with `separator` between the derivation and its outputs
+    fn parse_with(s: &str, separator: char) -> Result<Self> {
+        match s.split_once(separator) {
+            Some((drv_path, outputs)) => Ok(DerivedPath::Built {
+                drv_path: drv_path.parse()?,
+                outputs: outputs.parse().map_err(|_| Error::InvalidDerivedPath {
+                    path: s.to_string(),
+                    reason: "invalid output name",
+                })?,
+            }),
+            None => Ok(DerivedPath::Opaque(s.parse()?)),
+        }
+    }
+    /// parse the legacy `{drv}!{outputs}` syntax
+    pub fn parse_legacy(s: &str) -> Result<Self> {
+        Self::parse_with(s, '!')
+    }
+    /// render in the legacy `{drv}!{outputs}` syntax
+    pub fn to_string_legacy(&self) -> String {
+        match self {
+            DerivedPath::Opaque(path) => path.to_string(),
+            DerivedPath::Built { drv_path, outputs } => format!("{}!{}", drv_path, outputs),
+        }
+    }
+}
+
+impl std::str::FromStr for DerivedPath {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        Self::parse_with(s, '^')
+    }
+}
+
+impl std::fmt::Display for DerivedPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DerivedPath::Opaque(path) => write!(f, "{}", path),
+            DerivedPath::Built { drv_path, outputs } => write!(f, "{}^{}", drv_path, outputs),
+        }
+    }
+}
+
+/// since 1.30 the legacy syntax, before that a store path with outputs,
+/// where a derivation without outputs means all of them, so a `.drv` file
+/// itself can not be requested
+impl WorkerEncode for DerivedPath {
+    fn encode<W: std::io::Write>(&self, ser: &mut crate::ser::Serializer<W>) -> Result<()> {
+        if protocol_version_minor(ser.version()) >= 30 {
+            return self.to_string_legacy().encode(ser);
+        }
+        match self {
+            DerivedPath::Opaque(path) if path.is_derivation() => Err(Error::Unsupported(
+                "requesting a derivation file before protocol 1.30",
+            )),
+            DerivedPath::Opaque(path)
+            | DerivedPath::Built {
+                drv_path: path,
+                outputs: OutputsSpec::All,
+            } => path.encode(ser),
+            DerivedPath::Built { .. } => self.to_string_legacy().encode(ser),
+        }
+    }
+}
+
+impl WorkerDecode for DerivedPath {
+    fn decode<R: std::io::Read>(de: &mut crate::de::Deserializer<R>) -> Result<Self> {
+        let s = de.decode::<String>()?;
+        let path = Self::parse_legacy(&s)?;
+        if protocol_version_minor(de.version()) >= 30 {
+            return Ok(path);
+        }
+        match path {
+            DerivedPath::Opaque(path) if path.is_derivation() => Ok(DerivedPath::Built {
+                drv_path: path,
+                outputs: OutputsSpec::All,
+            }),
+            DerivedPath::Built { drv_path, .. } if !drv_path.is_derivation() => {
+                Err(Error::InvalidDerivedPath {
+                    path: s,
+                    reason: "outputs of a path that is not a derivation",
+                })
+            }
+            path => Ok(path),
+        }
+    }
+}
+
+crate::de::decode_async_as_string!(DerivedPath);
+
+/// a derived path after building, with the store paths of the outputs
+///
+/// rendered as `{drv}^{output}={path},...`, or with `!` in the legacy syntax,
+/// nix never sends it on the wire
+#[derive(Clone, Debug, PartialEq, Eq, std::hash::Hash)]
+pub enum BuiltPath {
+    Opaque(StorePath),
+    Built {
+        drv_path: StorePath,
+        outputs: BTreeMap<String, StorePath>,
+    },
+}
+
+impl BuiltPath {
+    /// the store paths this resolved to
+    pub fn out_paths(&self) -> Vec<&StorePath> {
+        match self {
+            BuiltPath::Opaque(path) => vec![path],
+            BuiltPath::Built { outputs, .. } => outputs.values().collect(),
+        }
+    }
+    /// the derived path that was built, naming the outputs
+    pub fn to_derived(&self) -> DerivedPath {
+        match self {
+            BuiltPath::Opaque(path) => DerivedPath::Opaque(path.clone()),
+            BuiltPath::Built { drv_path, outputs } => DerivedPath::Built {
+                drv_path: drv_path.clone(),
+                outputs: OutputsSpec::Names(outputs.keys().cloned().collect()),
+            },
+        }
+    }
+    /// parse with `separator` between the derivation and its outputs
+    fn parse_with(s: &str, separator: char) -> Result<Self> {
+        let invalid = |reason| Error::InvalidDerivedPath {
+            path: s.to_string(),
+            reason,
+        };
+        let (drv_path, outputs) = match s.split_once(separator) {
+            Some(x) => x,
+            None => return Ok(BuiltPath::Opaque(s.parse()?)),
+        };
+        let mut built = BTreeMap::new();
+        for output in outputs.split(',') {
+            // output names can not contain `/`, store paths start with it
+            let (name, path) = output
+                .split_once("=/")
+                .ok_or_else(|| invalid("missing output path"))?;
+            if !valid_output_name(name) {
+                return Err(invalid("invalid output name"));
+            }
+            let path = format!("/{}", path).parse()?;
+            if built.insert(name.to_string(), path).is_some() {
+                return Err(invalid("duplicate output"));
+            }
+        }
+        Ok(BuiltPath::Built {
+            drv_path: drv_path.parse()?,
+            outputs: built,
+        })
+    }
+    /// parse the legacy `{drv}!{output}={path},...` syntax
+    pub fn parse_legacy(s: &str) -> Result<Self> {
+        Self::parse_with(s, '!')
+    }
+    /// render with `separator` between the derivation and its outputs
+    fn to_string_with(&self, separator: char) -> String {
+        match self {
+            BuiltPath::Opaque(path) => path.to_string(),
+            BuiltPath::Built { drv_path, outputs } => {
+                let outputs: Vec<String> = outputs
+                    .iter()
+                    .map(|(name, path)| format!("{}={}", name, path))
+                    .collect();
+                format!("{}{}{}", drv_path, separator, outputs.join(","))
+            }
+        }
+    }
+    /// render in the legacy `{drv}!{output}={path},...` syntax
+    pub fn to_string_legacy(&self) -> String {
+        self.to_string_with('!')
+    }
+}
+
+impl std::str::FromStr for BuiltPath {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        Self::parse_with(s, '^')
+    }
+}
+
+impl std::fmt::Display for BuiltPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.to_string_with('^'))
+    }
+}
+
+#[test]
+fn test_parse() {
+    let drv: StorePath = "/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo.drv"
+        .parse()
+        .unwrap();
+    let names = |x: &[&str]| OutputsSpec::Names(x.iter().map(|x| x.to_string()).collect());
+    for (s, legacy, path) in [
+        (
+            "/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo",
+            "/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo",
+            DerivedPath::Opaque(
+                "/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo"
+                    .parse()
+                    .unwrap(),
+            ),
+        ),
+        (
+            "/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo.drv",
+            "/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo.drv",
+            DerivedPath::Opaque(drv.clone()),
+        ),
+        (
+            "/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo.drv^*",
+            "/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo.drv!*",
+            DerivedPath::Built {
+                drv_path: drv.clone(),
+                outputs: OutputsSpec::All,
+            },
+        ),
+        (
+            "/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo.drv^dev,out",
+            "/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo.drv!dev,out",
+            DerivedPath::Built {
+                drv_path: drv.clone(),
+                outputs: names(&["out", "dev"]),
+            },
+        ),
+    ] {
+        assert_eq!(s.parse::<DerivedPath>().unwrap(), path);
+        assert_eq!(DerivedPath::parse_legacy(legacy).unwrap(), path);
+        assert_eq!(path.to_string(), s);
+        assert_eq!(path.to_string_legacy(), legacy);
+    }
+    // outputs are a set
+    assert_eq!(
+        "/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo.drv^out,dev,out"
+            .parse::<DerivedPath>()
+            .unwrap()
+            .to_string(),
+        "/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo.drv^dev,out"
+    );
+    for s in [
+        "/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo.drv^",
+        "/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo.drv^out,",
+        "/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo.drv^out^dev",
+        "/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo.drv^*,out",
+    ] {
+        match s.parse::<DerivedPath>() {
+            Err(Error::InvalidDerivedPath { reason, .. }) => {
+                assert_eq!(reason, "invalid output name", "{}", s)
+            }
+            res => panic!("{}: {:?}", s, res),
+        }
+    }
+    assert!(matches!(
+        "/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo.drv!out".parse::<DerivedPath>(),
+        Err(Error::InvalidStorePath { .. })
+    ));
+
+    let built = BuiltPath::Built {
+        drv_path: drv,
+        outputs: [
+            (
+                String::from("out"),
+                "/nix/store/g1w7hyyyy1w7hyyyy1w7hyyyy1w7hyyy-foo"
+                    .parse()
+                    .unwrap(),
+            ),
+            (
+                String::from("dev"),
+                "/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo-dev"
+                    .parse()
+                    .unwrap(),
+            ),
+        ]
+        .into_iter()
+        .collect(),
+    };
+    let s = "/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo.drv^\
+dev=/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo-dev,\
+out=/nix/store/g1w7hyyyy1w7hyyyy1w7hyyyy1w7hyyy-foo";
+    assert_eq!(built.to_string(), s);
+    assert_eq!(s.parse::<BuiltPath>().unwrap(), built);
+    assert_eq!(built.to_string_legacy(), s.replace('^', "!"));
+    assert_eq!(
+        BuiltPath::parse_legacy(&built.to_string_legacy()).unwrap(),
+        built
+    );
+    assert_eq!(built.out_paths().len(), 2);
+    assert_eq!(
+        built.to_derived().to_string(),
+        "/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo.drv^dev,out"
+    );
+    let opaque: BuiltPath = "/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo"
+        .parse()
+        .unwrap();
+    assert_eq!(
+        opaque.to_string(),
+        "/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo"
+    );
+    assert_eq!(opaque.out_paths().len(), 1);
+
+    for (s, reason) in [
+        (
+            "/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo.drv^out",
+            "missing output path",
+        ),
+        (
+            "/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo.drv^",
+            "missing output path",
+        ),
+        (
+            "/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo.drv^=/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo",
+            "invalid output name",
+        ),
+        (
+            "/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo.drv^\
+out=/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo,\
+out=/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo",
+            "duplicate output",
+        ),
+    ] {
+        match s.parse::<BuiltPath>() {
+            Err(Error::InvalidDerivedPath { reason: r, .. }) => assert_eq!(r, reason, "{}", s),
+            res => panic!("{}: {:?}", s, res),
+        }
+    }
+}
+
+#[test]
+fn test_versions() {
+    use crate::de::Deserializer;
+    use crate::ser::Serializer;
+    let encode = |path: &DerivedPath, minor: u64| -> Result<String> {
+        let mut buf = vec![];
+        Serializer::with_version(&mut buf, 1 << 8 | minor).encode(path)?;
+        let s = Deserializer::from_slice_with_version(&buf, 1 << 8 | minor)
+            .decode::<String>()
+            .unwrap();
+        let decoded = Deserializer::from_slice_with_version(&buf, 1 << 8 | minor)
+            .decode::<DerivedPath>()
+            .unwrap();
+        assert_eq!(&decoded, path);
+        Ok(s)
+    };
+    let drv: StorePath = "/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo.drv"
+        .parse()
+        .unwrap();
+    let all = DerivedPath::Built {
+        drv_path: drv.clone(),
+        outputs: OutputsSpec::All,
+    };
+    assert_eq!(encode(&all, 30).unwrap(), format!("{}!*", drv));
+    assert_eq!(encode(&all, 29).unwrap(), drv.to_string());
+    let out = DerivedPath::Built {
+        drv_path: drv.clone(),
+        outputs: "out".parse().unwrap(),
+    };
+    assert_eq!(encode(&out, 29).unwrap(), format!("{}!out", drv));
+    let opaque = DerivedPath::Opaque(drv.clone());
+    assert_eq!(encode(&opaque, 30).unwrap(), drv.to_string());
+    assert!(matches!(encode(&opaque, 29), Err(Error::Unsupported(_))));
+
+    let mut buf = vec![];
+    Serializer::with_version(&mut buf, 1 << 8 | 29)
+        .encode("/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo!out")
+        .unwrap();
+    assert!(matches!(
+        Deserializer::from_slice_with_version(&buf, 1 << 8 | 29).decode::<DerivedPath>(),
+        Err(Error::InvalidDerivedPath { .. })
+    ));
+}
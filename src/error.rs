@@ -9,8 +9,94 @@ pub enum Error {
     Message(String),
     IO(std::io::Error),
     FromUtf8(std::string::FromUtf8Error),
+    Utf8(std::str::Utf8Error),
     TryFromInt(std::num::TryFromIntError),
     NotImplemented,
+    /// the wire format has no encoding for this kind of value
+    Unsupported(&'static str),
+    /// an integer on the wire does not fit the type it is decoded into
+    OutOfRange {
+        ty: &'static str,
+        value: u64,
+    },
+    /// the peer sent more than the deserializer is configured to accept
+    LimitExceeded {
+        what: &'static str,
+        value: u64,
+        limit: u64,
+    },
+    /// a path that is not a valid store path
+    InvalidStorePath {
+        path: String,
+        reason: &'static str,
+    },
+    /// a hash that does not parse
+    InvalidHash {
+        hash: String,
+        reason: &'static str,
+    },
+    /// a content address that does not parse
+    InvalidContentAddress {
+        ca: String,
+        reason: &'static str,
+    },
+    /// a signing key or signature that does not parse, only the name of the
+    /// key is kept so that secrets do not end up in logs
+    InvalidKey {
+        name: String,
+        reason: &'static str,
+    },
+    /// a derived path that does not parse
+    InvalidDerivedPath {
+        path: String,
+        reason: &'static str,
+    },
+    /// a `.narinfo` line or field that does not parse
+    InvalidNarInfo {
+        key: String,
+        reason: &'static str,
+    },
+    /// a `.drv` file that does not parse
+    InvalidDerivation {
+        offset: usize,
+        reason: &'static str,
+    },
+    /// an error while decoding, with the field path and the byte offset of
+    /// the value that failed
+    Decode {
+        path: String,
+        offset: u64,
+        error: Box<Error>,
+    },
+}
+
+impl Error {
+    /// prefix the field path of a decode error, innermost segment first
+    pub(crate) fn context(self, segment: &str, offset: u64) -> Self {
+        match self {
+            Error::Decode {
+                path,
+                offset,
+                error,
+            } => Error::Decode {
+                path: format!("{}{}", segment, path),
+                offset,
+                error,
+            },
+            error => Error::Decode {
+                path: segment.to_string(),
+                offset,
+                error: Box::new(error),
+            },
+        }
+    }
+    /// the underlying error with any decode context removed
+    pub fn root(&self) -> &Error {
+        match self {
+            Error::Decode { error, .. } => error.root(),
+            error => error,
+        }
+    }
 }
 
 impl std::error::Error for Error {}
@@ -33,8 +119,53 @@ impl fmt::Display for Error {
             Error::Message(msg) => formatter.write_str(msg),
             Error::IO(e) => e.fmt(formatter),
             Error::FromUtf8(e) => e.fmt(formatter),
+            Error::Utf8(e) => e.fmt(formatter),
             Error::TryFromInt(e) => e.fmt(formatter),
             Error::NotImplemented => formatter.write_str("not implemented"),
+            Error::Unsupported(what) => write!(formatter, "{} is not supported", what),
+            Error::OutOfRange { ty, value } => {
+                write!(formatter, "{} is out of range for {}", value, ty)
+            }
+            Error::LimitExceeded { what, value, limit } => {
+                write!(
+                    formatter,
+                    "{} {} exceeds the limit of {}",
+                    what, value, limit
+                )
+            }
+            Error::InvalidStorePath { path, reason } => {
+                write!(formatter, "invalid store path {:?}: {}", path, reason)
+            }
+            Error::InvalidHash { hash, reason } => {
+                write!(formatter, "invalid hash {:?}: {}", hash, reason)
+            }
+            Error::InvalidContentAddress { ca, reason } => {
+                write!(formatter, "invalid content address {:?}: {}", ca, reason)
+            }
+            Error::InvalidKey { name, reason } => {
+                write!(formatter, "invalid key {:?}: {}", name, reason)
+            }
+            Error::InvalidDerivedPath { path, reason } => {
+                write!(formatter, "invalid derived path {:?}: {}", path, reason)
+            }
+            Error::InvalidNarInfo { key, reason } => {
+                write!(formatter, "invalid narinfo field {:?}: {}", key, reason)
+            }
+            Error::InvalidDerivation { offset, reason } => {
+                write!(
+                    formatter,
+                    "invalid derivation at byte {}: {}",
+                    offset, reason
+                )
+            }
+            Error::Decode {
+                path,
+                offset,
+                error,
+            } => match path.strip_prefix('.').unwrap_or(path) {
+                "" => write!(formatter, "{} at offset {}", error, offset),
+                path => write!(formatter, "{}: {} at offset {}", path, error, offset),
+            },
         }
     }
 }
@@ -51,6 +182,12 @@ impl From<std::string::FromUtf8Error> for Error {
     }
 }
 
+impl From<std::str::Utf8Error> for Error {
+    fn from(e: std::str::Utf8Error) -> Self {
+        Self::Utf8(e)
+    }
+}
+
 impl From<std::num::TryFromIntError> for Error {
     fn from(e: std::num::TryFromIntError) -> Self {
         Self::TryFromInt(e)
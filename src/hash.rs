@@ -0,0 +1,386 @@
+//! hashes in the textual forms nix uses
+//!
+//! adapated from <https://github.com/NixOS/nix/blob/master/src/libutil/hash.cc>
+
+use crate::error::{Error, Result};
+use serde::{Deserialize, Serialize};
+
+/// digits of nix base32, without e, o, u and t
+pub(crate) const NIX_BASE32_CHARS: &[u8] = b"0123456789abcdfghijklmnpqrsvwxyz";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, std::hash::Hash)]
+pub enum HashAlgo {
+    Md5,
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+impl HashAlgo {
+    pub fn name(&self) -> &'static str {
+        match self {
+            HashAlgo::Md5 => "md5",
+            HashAlgo::Sha1 => "sha1",
+            HashAlgo::Sha256 => "sha256",
+            HashAlgo::Sha512 => "sha512",
+        }
+    }
+    /// digest length in bytes
+    pub fn size(&self) -> usize {
+        match self {
+            HashAlgo::Md5 => 16,
+            HashAlgo::Sha1 => 20,
+            HashAlgo::Sha256 => 32,
+            HashAlgo::Sha512 => 64,
+        }
+    }
+}
+
+impl std::str::FromStr for HashAlgo {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "md5" => Ok(HashAlgo::Md5),
+            "sha1" => Ok(HashAlgo::Sha1),
+            "sha256" => Ok(HashAlgo::Sha256),
+            "sha512" => Ok(HashAlgo::Sha512),
+            _ => Err(Error::InvalidHash {
+                hash: s.to_string(),
+                reason: "unknown hash algorithm",
+            }),
+        }
+    }
+}
+
+impl std::fmt::Display for HashAlgo {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// encodings of a digest
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HashFormat {
+    Base16,
+    Nix32,
+    Base64,
+}
+
+impl HashFormat {
+    /// length of a digest of `size` bytes in this format
+    pub fn len(&self, size: usize) -> usize {
+        match self {
+            HashFormat::Base16 => size * 2,
+            HashFormat::Nix32 => (size * 8 - 1) / 5 + 1,
+            HashFormat::Base64 => size.div_ceil(3) * 4,
+        }
+    }
+}
+
+/// a digest together with its algorithm
+///
+/// `Display` gives the SRI form, `sha256-<base64>`
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, std::hash::Hash)]
+pub struct Hash {
+    algo: HashAlgo,
+    digest: Vec<u8>,
+}
+
+impl Hash {
+    pub fn from_digest(algo: HashAlgo, digest: &[u8]) -> Result<Self> {
+        if digest.len() != algo.size() {
+            return Err(Error::InvalidHash {
+                hash: encode_base16(digest),
+                reason: "wrong digest length",
+            });
+        }
+        Ok(Self {
+            algo,
+            digest: digest.to_vec(),
+        })
+    }
+    pub fn sha256(data: &[u8]) -> Self {
+        use sha2::Digest;
+        Self {
+            algo: HashAlgo::Sha256,
+            digest: sha2::Sha256::digest(data).to_vec(),
+        }
+    }
+    /// hash `data` with `algo`
+    pub fn compute(algo: HashAlgo, data: &[u8]) -> Self {
+        use sha2::Digest;
+        let digest = match algo {
+            HashAlgo::Md5 => md5::Md5::digest(data).to_vec(),
+            HashAlgo::Sha1 => sha1::Sha1::digest(data).to_vec(),
+            HashAlgo::Sha256 => sha2::Sha256::digest(data).to_vec(),
+            HashAlgo::Sha512 => sha2::Sha512::digest(data).to_vec(),
+        };
+        Self { algo, digest }
+    }
+    pub fn algo(&self) -> HashAlgo {
+        self.algo
+    }
+    pub fn digest(&self) -> &[u8] {
+        &self.digest
+    }
+    /// parse `algo:digest`, `algo-base64` or, if `algo` is given, a bare digest
+    ///
+    /// the digest may be in any of the formats, told apart by its length
+    pub fn parse_any(s: &str, algo: Option<HashAlgo>) -> Result<Self> {
+        let invalid = |reason| Error::InvalidHash {
+            hash: s.to_string(),
+            reason,
+        };
+        let (prefix, digest, sri) = if let Some((prefix, digest)) = s.split_once(':') {
+            (Some(prefix), digest, false)
+        } else if let Some((prefix, digest)) = s.split_once('-') {
+            (Some(prefix), digest, true)
+        } else {
+            (None, s, false)
+        };
+        let algo = match (prefix.map(str::parse::<HashAlgo>).transpose()?, algo) {
+            (Some(a), Some(b)) if a != b => return Err(invalid("unexpected hash algorithm")),
+            (Some(a), _) | (None, Some(a)) => a,
+            (None, None) => return Err(invalid("missing hash algorithm")),
+        };
+        let size = algo.size();
+        let formats: &[HashFormat] = if sri {
+            &[HashFormat::Base64]
+        } else {
+            &[HashFormat::Base16, HashFormat::Nix32, HashFormat::Base64]
+        };
+        let digest = match formats.iter().find(|x| x.len(size) == digest.len()) {
+            Some(HashFormat::Base16) => decode_base16(digest),
+            Some(HashFormat::Nix32) => decode_nix32(digest, size),
+            Some(HashFormat::Base64) => base64::decode(digest).ok(),
+            None => return Err(invalid("wrong digest length")),
+        };
+        match digest {
+            Some(digest) if digest.len() == size => Ok(Self { algo, digest }),
+            _ => Err(invalid("invalid digest")),
+        }
+    }
+    /// the digest alone
+    pub fn encode(&self, format: HashFormat) -> String {
+        match format {
+            HashFormat::Base16 => encode_base16(&self.digest),
+            HashFormat::Nix32 => encode_nix32(&self.digest),
+            HashFormat::Base64 => base64::encode(&self.digest),
+        }
+    }
+    /// `algo:digest`
+    pub fn to_prefixed(&self, format: HashFormat) -> String {
+        format!("{}:{}", self.algo, self.encode(format))
+    }
+    /// `algo-base64`
+    pub fn to_sri(&self) -> String {
+        format!("{}-{}", self.algo, self.encode(HashFormat::Base64))
+    }
+}
+
+impl std::str::FromStr for Hash {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        Hash::parse_any(s, None)
+    }
+}
+
+impl std::fmt::Display for Hash {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.to_sri())
+    }
+}
+
+/// `algo:base16`, as in derivation outputs and realisations
+impl Serialize for Hash {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_prefixed(HashFormat::Base16))
+    }
+}
+
+impl<'de> Deserialize<'de> for Hash {
+    fn deserialize<D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// bare base16 like nar hashes in path infos, any form with sha256 as the
+/// default algorithm is accepted when decoding. the algorithm is not sent,
+/// so other hashes can not be encoded
+impl crate::ser::WorkerEncode for Hash {
+    fn encode<W: std::io::Write>(&self, ser: &mut crate::ser::Serializer<W>) -> Result<()> {
+        if self.algo != HashAlgo::Sha256 {
+            return Err(Error::Unsupported("a bare hash other than sha256"));
+        }
+        self.encode(HashFormat::Base16).encode(ser)
+    }
+}
+
+impl crate::de::WorkerDecode for Hash {
+    fn decode<R: std::io::Read>(de: &mut crate::de::Deserializer<R>) -> Result<Self> {
+        Hash::parse_any(&de.decode::<String>()?, Some(HashAlgo::Sha256))
+    }
+}
+
+crate::de::decode_async_as_string!(Hash);
+
+fn encode_base16(bytes: &[u8]) -> String {
+    bytes.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+fn decode_base16(s: &str) -> Option<Vec<u8>> {
+    s.as_bytes()
+        .chunks(2)
+        .map(|x| match x {
+            [a, b] => Some(((*a as char).to_digit(16)? << 4 | (*b as char).to_digit(16)?) as u8),
+            _ => None,
+        })
+        .collect()
+}
+
+/// nix base32 starts with the last bits of the digest
+pub fn encode_nix32(bytes: &[u8]) -> String {
+    let len = HashFormat::Nix32.len(bytes.len());
+    (0..len)
+        .rev()
+        .map(|n| {
+            let b = n * 5;
+            let (i, j) = (b / 8, b % 8);
+            let c = (bytes[i] >> j) as u16 | bytes.get(i + 1).map_or(0, |x| (*x as u16) << (8 - j));
+            NIX_BASE32_CHARS[(c & 0x1f) as usize] as char
+        })
+        .collect()
+}
+
+/// decode nix base32 into `size` bytes
+pub fn decode_nix32(s: &str, size: usize) -> Option<Vec<u8>> {
+    let mut bytes = vec![0; size];
+    for (n, c) in s.bytes().rev().enumerate() {
+        let digit = NIX_BASE32_CHARS.iter().position(|x| *x == c)? as u16;
+        let b = n * 5;
+        let (i, j) = (b / 8, b % 8);
+        *bytes.get_mut(i)? |= (digit << j) as u8;
+        let carry = (digit << j >> 8) as u8;
+        match bytes.get_mut(i + 1) {
+            Some(x) => *x |= carry,
+            None if carry != 0 => return None,
+            None => (),
+        }
+    }
+    Some(bytes)
+}
+
+#[test]
+fn test_formats() {
+    for (algo, base16, nix32, base64) in [
+        (
+            HashAlgo::Md5,
+            "900150983cd24fb0d6963f7d28e17f72",
+            "3jgzhjhz9zjvbb0kyj7jc500ch",
+            "kAFQmDzST7DWlj99KOF/cg==",
+        ),
+        (
+            HashAlgo::Sha1,
+            "a9993e364706816aba3e25717850c26c9cd0d89d",
+            "kpcd173cq987hw957sx6m0868wv3x6d9",
+            "qZk+NkcGgWq6PiVxeFDCbJzQ2J0=",
+        ),
+        (
+            HashAlgo::Sha256,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+            "1b8m03r63zqhnjf7l5wnldhh7c134ap5vpj0850ymkq1iyzicy5s",
+            "ungWv48Bz+pBQUDeXa4iI7ADYaOWF3qctBD/YfIAFa0=",
+        ),
+        (
+            HashAlgo::Sha512,
+            "ddaf35a193617abacc417349ae20413112e6fa4e89a97ea20a9eeee64b55d39a2192992a274fc1a836ba3c23a3feebbd454d4423643ce80e2a9ac94fa54ca49f",
+            "2gs8k559z4rlahfx0y688s49m2vvszylcikrfinm30ly9rak69236nkam5ydvly1ai7xac99vxfc4ii84hawjbk876blyk1jfhkbbyx",
+            "3a81oZNherrMQXNJriBBMRLm+k6JqX6iCp7u5ktV05ohkpkqJ0/BqDa6PCOj/uu9RU1EI2Q86A4qmslPpUyknw==",
+        ),
+    ] {
+        let hash = Hash::parse_any(base16, Some(algo)).unwrap();
+        assert_eq!(Hash::compute(algo, b"abc"), hash);
+        assert_eq!(hash.encode(HashFormat::Base16), base16);
+        assert_eq!(hash.encode(HashFormat::Nix32), nix32);
+        assert_eq!(hash.encode(HashFormat::Base64), base64);
+        for s in [
+            format!("{}:{}", algo, base16),
+            format!("{}:{}", algo, nix32),
+            format!("{}:{}", algo, base64),
+            format!("{}-{}", algo, base64),
+        ] {
+            assert_eq!(s.parse::<Hash>().unwrap(), hash, "{}", s);
+        }
+        assert_eq!(Hash::parse_any(nix32, Some(algo)).unwrap(), hash);
+        assert_eq!(hash.to_string(), format!("{}-{}", algo, base64));
+        assert_eq!(
+            hash.to_prefixed(HashFormat::Nix32),
+            format!("{}:{}", algo, nix32)
+        );
+    }
+}
+
+#[test]
+fn test_worker() {
+    use crate::de::Deserializer;
+    use crate::ser::Serializer;
+    let sha256 = Hash::sha256(b"abc");
+    let mut buf = vec![];
+    Serializer::new(&mut buf).encode(&sha256).unwrap();
+    assert_eq!(
+        Deserializer::from_slice(&buf).decode::<Hash>().unwrap(),
+        sha256
+    );
+
+    let sha512 = Hash::from_digest(HashAlgo::Sha512, &[0; 64]).unwrap();
+    let mut buf = vec![];
+    assert!(matches!(
+        Serializer::new(&mut buf).encode(&sha512),
+        Err(Error::Unsupported(_))
+    ));
+    let mut buf = vec![];
+    Serializer::new(&mut buf)
+        .encode(&sha512.to_prefixed(HashFormat::Base16))
+        .unwrap();
+    assert!(Deserializer::from_slice(&buf).decode::<Hash>().is_err());
+}
+
+#[test]
+fn test_invalid() {
+    let sha256 = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+    for (s, algo, reason) in [
+        (sha256, None, "missing hash algorithm"),
+        ("sha256:abcd", None, "wrong digest length"),
+        ("sha3:abcd", None, "unknown hash algorithm"),
+        (&format!("sha1:{}", sha256), None, "wrong digest length"),
+        (
+            &format!("sha256:{}", sha256),
+            Some(HashAlgo::Sha512),
+            "unexpected hash algorithm",
+        ),
+        (
+            "sha256:1b8m03r63zqhnjf7l5wnldhh7c134ap5vpj0850ymkq1iyzicy5e",
+            None,
+            "invalid digest",
+        ),
+        // the top bits of the first digit do not fit in 32 bytes
+        (
+            "sha256:zb8m03r63zqhnjf7l5wnldhh7c134ap5vpj0850ymkq1iyzicy5s",
+            None,
+            "invalid digest",
+        ),
+        (&format!("sha256-{}", sha256), None, "wrong digest length"),
+    ] {
+        match Hash::parse_any(s, algo) {
+            Err(Error::InvalidHash { reason: r, .. }) => assert_eq!(r, reason, "{}", s),
+            res => panic!("{}: {:?}", s, res),
+        }
+    }
+}
@@ -1,7 +1,18 @@
+// lets the derive macros refer to ::sirius from inside this crate
+extern crate self as sirius;
+
 pub mod client;
 pub mod consts;
+pub mod content_address;
 pub mod de;
+pub mod derivation;
+pub mod derived_path;
 pub mod error;
+pub mod hash;
+pub mod narinfo;
 pub mod protocol;
 pub mod ser;
+pub mod server;
+pub mod signing;
+pub mod store_path;
 pub mod types;
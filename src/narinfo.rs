@@ -0,0 +1,273 @@
+//! `.narinfo` files, how binary caches describe the store paths they serve
+//!
+//! each line is `{key}: {value}`, store paths other than `StorePath` are
+//! written as base names
+//!
+//! adapated from <https://github.com/NixOS/nix/blob/master/src/libstore/nar-info.cc>
+
+use crate::error::{Error, Result};
+use crate::hash::{Hash, HashFormat};
+use crate::store_path::StorePath;
+use crate::types::{UnkeyedValidPathInfo, ValidPathInfo};
+
+/// what nix assumes when a narinfo has no `Compression`
+const DEFAULT_COMPRESSION: &str = "bzip2";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NarInfo {
+    /// `registration_time` and `ultimate` are not part of the format and are
+    /// left zero
+    pub info: ValidPathInfo,
+    /// where the (compressed) nar is, relative to the cache
+    pub url: String,
+    pub compression: String,
+    pub file_hash: Option<Hash>,
+    pub file_size: Option<u64>,
+    /// keys this crate does not know, kept in order so that they survive a
+    /// round trip
+    pub extra: Vec<(String, String)>,
+}
+
+impl NarInfo {
+    /// the name of the file in the cache, `{hash_part}.narinfo`
+    pub fn file_name(&self) -> String {
+        format!("{}.narinfo", self.info.path.hash_part())
+    }
+}
+
+fn invalid(key: &str, reason: &'static str) -> Error {
+    Error::InvalidNarInfo {
+        key: key.to_string(),
+        reason,
+    }
+}
+
+fn parse_u64(key: &str, value: &str) -> Result<u64> {
+    value.parse().map_err(|_| invalid(key, "not a number"))
+}
+
+/// hashes need their algorithm, SRI is accepted as nix does
+fn parse_hash(key: &str, value: &str) -> Result<Hash> {
+    match value.split_once(':') {
+        Some((algo, _)) => Hash::parse_any(value, Some(algo.parse()?)),
+        None if value.contains('-') => value.parse(),
+        None => Err(invalid(key, "missing hash algorithm")),
+    }
+}
+
+impl std::str::FromStr for NarInfo {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let mut path = None;
+        let mut url = None;
+        let mut compression = None;
+        let mut file_hash = None;
+        let mut file_size = None;
+        let mut nar_hash = None;
+        let mut nar_size = None;
+        let mut references = None;
+        let mut deriver = None;
+        let mut sigs = vec![];
+        let mut ca = None;
+        let mut extra = vec![];
+        // every known key but `Sig` may appear once
+        fn set<T>(slot: &mut Option<T>, key: &str, value: T) -> Result<()> {
+            match slot.replace(value) {
+                Some(_) => Err(invalid(key, "duplicate key")),
+                None => Ok(()),
+            }
+        }
+        for line in s.lines().filter(|x| !x.is_empty()) {
+            // an empty `References` may have lost its trailing space
+            let (key, value) = line
+                .split_once(':')
+                .ok_or_else(|| invalid(line, "missing colon"))?;
+            let value = value.strip_prefix(' ').unwrap_or(value);
+            match key {
+                "StorePath" => set(&mut path, key, value.parse::<StorePath>()?)?,
+                "URL" => set(&mut url, key, value.to_string())?,
+                "Compression" => set(&mut compression, key, value.to_string())?,
+                "FileHash" => set(&mut file_hash, key, parse_hash(key, value)?)?,
+                "FileSize" => set(&mut file_size, key, parse_u64(key, value)?)?,
+                "NarHash" => set(&mut nar_hash, key, parse_hash(key, value)?)?,
+                "NarSize" => set(&mut nar_size, key, parse_u64(key, value)?)?,
+                "References" => set(
+                    &mut references,
+                    key,
+                    value
+                        .split_whitespace()
+                        .map(StorePath::from_base_name)
+                        .collect::<Result<Vec<_>>>()?,
+                )?,
+                "Deriver" => set(
+                    &mut deriver,
+                    key,
+                    match value {
+                        "unknown-deriver" => None,
+                        _ => Some(StorePath::from_base_name(value)?),
+                    },
+                )?,
+                "Sig" => sigs.push(value.to_string()),
+                "CA" => set(&mut ca, key, value.parse()?)?,
+                _ => extra.push((key.to_string(), value.to_string())),
+            }
+        }
+        Ok(NarInfo {
+            info: ValidPathInfo {
+                path: path.ok_or_else(|| invalid("StorePath", "missing"))?,
+                info: UnkeyedValidPathInfo {
+                    deriver: deriver.flatten(),
+                    hash: nar_hash.ok_or_else(|| invalid("NarHash", "missing"))?,
+                    references: references.unwrap_or_default(),
+                    registration_time: 0,
+                    nar_size: nar_size.ok_or_else(|| invalid("NarSize", "missing"))?,
+                    ultimate: false,
+                    sigs,
+                    ca,
+                },
+            },
+            url: url.ok_or_else(|| invalid("URL", "missing"))?,
+            compression: compression.unwrap_or_else(|| DEFAULT_COMPRESSION.to_string()),
+            file_hash,
+            file_size,
+            extra,
+        })
+    }
+}
+
+impl std::fmt::Display for NarInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let info = &self.info.info;
+        writeln!(f, "StorePath: {}", self.info.path)?;
+        writeln!(f, "URL: {}", self.url)?;
+        writeln!(f, "Compression: {}", self.compression)?;
+        if let Some(hash) = &self.file_hash {
+            writeln!(f, "FileHash: {}", hash.to_prefixed(HashFormat::Nix32))?;
+        }
+        if let Some(size) = self.file_size {
+            writeln!(f, "FileSize: {}", size)?;
+        }
+        writeln!(f, "NarHash: {}", info.hash.to_prefixed(HashFormat::Nix32))?;
+        writeln!(f, "NarSize: {}", info.nar_size)?;
+        let references: Vec<&str> = info.references.iter().map(|x| x.base_name()).collect();
+        writeln!(f, "References: {}", references.join(" "))?;
+        if let Some(deriver) = &info.deriver {
+            writeln!(f, "Deriver: {}", deriver.base_name())?;
+        }
+        for sig in &info.sigs {
+            writeln!(f, "Sig: {}", sig)?;
+        }
+        if let Some(ca) = &info.ca {
+            writeln!(f, "CA: {}", ca)?;
+        }
+        for (key, value) in &self.extra {
+            writeln!(f, "{}: {}", key, value)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+const HELLO_NARINFO: &str = "\
+StorePath: /nix/store/pa10z4ngm0g83kx9mssrqzz30s84vq7k-hello-2.12.1.tar.gz
+URL: nar/086vqwk2wl8zfs47sq2xpjc9k066ilmb8z6dn0q6ymwjzlm196cd.nar.xz
+Compression: xz
+FileHash: sha256:086vqwk2wl8zfs47sq2xpjc9k066ilmb8z6dn0q6ymwjzlm196cd
+FileSize: 1009
+NarHash: sha256:1lr187v6dck1rjh2j6svpikcfz53wyl3qrlcbb405zlh13x0khhh
+NarSize: 1234
+References: 9lkz5r8b5srq6z3x5nzi1k8d5mgr4z16-glibc-2.35 pa10z4ngm0g83kx9mssrqzz30s84vq7k-hello-2.12.1.tar.gz
+Deriver: g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-hello-2.12.1.tar.gz.drv
+Sig: fake-sig-1
+Sig: fake-sig-2
+CA: fixed:r:sha256:1lr187v6dck1rjh2j6svpikcfz53wyl3qrlcbb405zlh13x0khhh
+";
+
+#[test]
+fn test_parse() {
+    let narinfo: NarInfo = HELLO_NARINFO.parse().unwrap();
+    assert_eq!(narinfo.info.path.name(), "hello-2.12.1.tar.gz");
+    assert_eq!(
+        narinfo.file_name(),
+        "pa10z4ngm0g83kx9mssrqzz30s84vq7k.narinfo"
+    );
+    assert_eq!(narinfo.compression, "xz");
+    assert_eq!(narinfo.file_size, Some(1009));
+    assert_eq!(narinfo.info.info.nar_size, 1234);
+    assert_eq!(narinfo.info.info.references.len(), 2);
+    assert_eq!(
+        narinfo.info.info.deriver.as_ref().unwrap().to_string(),
+        "/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-hello-2.12.1.tar.gz.drv"
+    );
+    assert_eq!(narinfo.info.info.sigs, ["fake-sig-1", "fake-sig-2"]);
+    assert!(narinfo.info.info.ca.is_some());
+    assert_eq!(narinfo.to_string(), HELLO_NARINFO);
+
+    // optional fields, other hash formats and unknown keys
+    let narinfo: NarInfo = "\
+StorePath: /nix/store/pa10z4ngm0g83kx9mssrqzz30s84vq7k-hello-2.12.1.tar.gz
+URL: nar/foo.nar
+NarHash: sha256-FePFYIlMuycIXPZbWi7LGEiMmZSX9FMbaQenWBzm1Sc=
+NarSize: 1
+References:
+Deriver: unknown-deriver
+System: x86_64-linux
+"
+    .parse()
+    .unwrap();
+    assert_eq!(narinfo.compression, "bzip2");
+    assert_eq!(narinfo.file_hash, None);
+    assert_eq!(narinfo.info.info.references, []);
+    assert_eq!(narinfo.info.info.deriver, None);
+    assert_eq!(
+        narinfo.extra,
+        [(String::from("System"), String::from("x86_64-linux"))]
+    );
+    assert_eq!(narinfo.to_string().parse::<NarInfo>().unwrap(), narinfo);
+
+    for (s, key, reason) in [
+        (
+            "URL: a\nNarHash: sha256-FePFYIlMuycIXPZbWi7LGEiMmZSX9FMbaQenWBzm1Sc=\nNarSize: 1",
+            "StorePath",
+            "missing",
+        ),
+        (
+            "StorePath: /nix/store/pa10z4ngm0g83kx9mssrqzz30s84vq7k-a\nURL: a\nNarSize: 1",
+            "NarHash",
+            "missing",
+        ),
+        ("URL: a\nURL: b", "URL", "duplicate key"),
+        ("URL a", "URL a", "missing colon"),
+        ("NarSize: -1", "NarSize", "not a number"),
+        (
+            "FileHash: 086vqwk2wl8zfs47sq2xpjc9k066ilmb8z6dn0q6ymwjzlm196cd",
+            "FileHash",
+            "missing hash algorithm",
+        ),
+    ] {
+        match s.parse::<NarInfo>() {
+            Err(Error::InvalidNarInfo { key: k, reason: r }) => {
+                assert_eq!((k.as_str(), r), (key, reason), "{}", s)
+            }
+            res => panic!("{}: {:?}", s, res),
+        }
+    }
+}
+
+#[test]
+fn test_sign() {
+    let key: crate::signing::SecretKey = "test-1:nWGxne/9WmC6hEr0kuwsxERJxWl7MmkZcDusAxyuf2DXWpgBgrEKt9VL/tPJZAc6DuFy89qmIyWvAhpo9wdRGg=="
+        .parse()
+        .unwrap();
+    let mut narinfo: NarInfo = HELLO_NARINFO.parse().unwrap();
+    narinfo.info.info.sigs.clear();
+    narinfo.info.sign(&key).unwrap();
+    let narinfo: NarInfo = narinfo.to_string().parse().unwrap();
+    assert_eq!(
+        narinfo
+            .info
+            .check_signatures(&[key.to_public_key()])
+            .unwrap(),
+        1
+    );
+}
@@ -7,7 +7,8 @@ use serde_repr::{Deserialize_repr, Serialize_repr};
 
 pub const WORKER_MAGIC_1: u64 = 0x6e697863;
 pub const WORKER_MAGIC_2: u64 = 0x6478696f;
-pub const PROTOCOL_VERSION: u64 = 1 << 8 | 34;
+pub const PROTOCOL_VERSION: u64 = 1 << 8 | 37;
+pub const MIN_PROTOCOL_VERSION: u64 = 1 << 8 | 21;
 
 pub const STDERR_NEXT: u64 = 0x6f6c6d67;
 pub const STDERR_READ: u64 = 0x64617461;
@@ -26,7 +27,7 @@ pub fn protocol_version_minor(version: u64) -> u64 {
     version & 0x00ff
 }
 
-#[derive(Serialize_repr, Deserialize_repr, Debug)]
+#[derive(Serialize_repr, Deserialize_repr, Debug, PartialEq, Eq)]
 #[repr(u64)]
 pub enum Op {
     Nop = 0,
@@ -1,6 +1,8 @@
 use crate::error::Error;
+use crate::protocol::PROTOCOL_VERSION;
 use serde::ser;
 use serde::Serialize;
+pub use sirius_derive::WorkerEncode;
 
 pub struct SeqSerializer<'a, 'b, T> {
     ser: &'a mut Serializer<'b, T>,
@@ -119,12 +121,6 @@ impl<'a, 'b, W: std::io::Write> ser::SerializeMap for SeqSerializer<'a, 'b, W> {
     type Ok = ();
     type Error = Error;
     fn serialize_key<T: ?Sized>(&mut self, key: &T) -> Result<(), Self::Error>
-    where
-        T: Serialize,
-    {
-        key.serialize(&mut *self.ser)
-    }
-    fn serialize_value<T: ?Sized>(&mut self, value: &T) -> Result<(), Self::Error>
     where
         T: Serialize,
     {
@@ -136,6 +132,12 @@ impl<'a, 'b, W: std::io::Write> ser::SerializeMap for SeqSerializer<'a, 'b, W> {
             return Err(Self::Error::Message("too many elements".to_string()));
         }
         self.remain -= 1;
+        key.serialize(&mut *self.ser)
+    }
+    fn serialize_value<T: ?Sized>(&mut self, value: &T) -> Result<(), Self::Error>
+    where
+        T: Serialize,
+    {
         value.serialize(&mut *self.ser)
     }
     fn end(self) -> Result<Self::Ok, Self::Error> {
@@ -155,17 +157,79 @@ implement_serialize_tuple_struct!(ser::SerializeTupleVariant);
 implement_serialize_struct!(ser::SerializeStruct);
 implement_serialize_struct!(ser::SerializeStructVariant);
 
+pub(crate) const STRING_TAGGED: &str = "$sirius::StringTagged";
+
+/// sends the variant of the wrapped enum by name rather than by index
+///
+/// enum variants otherwise go on the wire as their u64 index followed by their
+/// fields, the wrapper has to be directly around the enum to take effect
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StringTagged<T>(pub T);
+
+impl<T: Serialize> Serialize for StringTagged<T> {
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_newtype_struct(STRING_TAGGED, &self.0)
+    }
+}
+
 pub struct Serializer<'a, W> {
     write: &'a mut W,
+    version: u64,
+    // set by `StringTagged` for the value right inside it, anything written
+    // before a variant clears it
+    string_tag: bool,
 }
 
 impl<'a, W: std::io::Write> Serializer<'a, W> {
     pub fn new(write: &'a mut W) -> Self {
-        Self { write }
+        Self::with_version(write, PROTOCOL_VERSION)
+    }
+    pub fn with_version(write: &'a mut W, version: u64) -> Self {
+        Self {
+            write,
+            version,
+            string_tag: false,
+        }
+    }
+    /// protocol version negotiated with the peer
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+    pub fn encode<T: WorkerEncode + ?Sized>(&mut self, value: &T) -> crate::error::Result<()> {
+        value.encode(self)
     }
     fn write_u64(&mut self, v: u64) -> crate::error::Result<()> {
+        self.string_tag = false;
         Ok(self.write.write_all(&v.to_le_bytes())?)
     }
+    /// stream `len` bytes from `read` as a length-prefixed byte field
+    ///
+    /// produces the same bytes as serializing a buffer holding the data,
+    /// without the buffer
+    pub fn serialize_reader<T: std::io::Read>(
+        &mut self,
+        len: u64,
+        read: T,
+    ) -> crate::error::Result<()> {
+        self.write_u64(len)?;
+        let size = std::io::copy(&mut read.take(len), &mut *self.write)?;
+        if size != len {
+            return Err(Error::IO(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!("reader ended after {} of {} bytes", size, len),
+            )));
+        }
+        let rem = len % 8;
+        let pad = if rem == 0 { 0 } else { 8 - rem };
+        Ok(self.write.write_all(&[0; 8][..pad as usize])?)
+    }
+    fn write_variant(&mut self, index: u32, name: &str) -> crate::error::Result<()> {
+        if std::mem::take(&mut self.string_tag) {
+            self.write_bytes(name.as_bytes())
+        } else {
+            self.write_u64(index.into())
+        }
+    }
     fn write_bytes(&mut self, v: &[u8]) -> crate::error::Result<()> {
         let len = v.len();
         let rem = len % 8;
@@ -201,21 +265,30 @@ impl<'a, 'b, W: std::io::Write> ser::Serializer for &'b mut Serializer<'a, W> {
     type SerializeStruct = SeqSerializer<'b, 'a, W>;
     type SerializeStructVariant = SeqSerializer<'b, 'a, W>;
 
-    serialize_unimplemented!(serialize_i8, _v: i8);
-    serialize_unimplemented!(serialize_i16, _v: i16);
-    serialize_unimplemented!(serialize_i32, _v: i32);
-    serialize_unimplemented!(serialize_i64, _v: i64);
     serialize_unimplemented!(serialize_f32, _v: f32);
     serialize_unimplemented!(serialize_f64, _v: f64);
     serialize_unimplemented!(serialize_char, _v: char);
-    serialize_unimplemented!(serialize_unit);
-    serialize_unimplemented!(serialize_unit_struct, _name: &'static str);
-    serialize_unimplemented!(
-        serialize_unit_variant,
-        _name: &'static str,
-        _variant_index: u32,
-        _variant: &'static str
-    );
+
+    fn serialize_unit(self) -> Result<Self::Ok, Self::Error> {
+        self.string_tag = false;
+        Ok(())
+    }
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Self::Ok, Self::Error> {
+        self.string_tag = false;
+        Ok(())
+    }
+    fn serialize_i8(self, v: i8) -> Result<Self::Ok, Self::Error> {
+        self.serialize_u64(v.try_into()?)
+    }
+    fn serialize_i16(self, v: i16) -> Result<Self::Ok, Self::Error> {
+        self.serialize_u64(v.try_into()?)
+    }
+    fn serialize_i32(self, v: i32) -> Result<Self::Ok, Self::Error> {
+        self.serialize_u64(v.try_into()?)
+    }
+    fn serialize_i64(self, v: i64) -> Result<Self::Ok, Self::Error> {
+        self.serialize_u64(v.try_into()?)
+    }
 
     fn serialize_bool(self, v: bool) -> Result<Self::Ok, Self::Error> {
         self.serialize_u64(v.into())
@@ -246,26 +319,38 @@ impl<'a, 'b, W: std::io::Write> ser::Serializer for &'b mut Serializer<'a, W> {
         self.serialize_bool(true)?;
         value.serialize(self)
     }
-    fn serialize_newtype_struct<T: ?Sized>(
+    fn serialize_unit_variant(
         self,
         _name: &'static str,
+        variant_index: u32,
+        variant: &'static str,
+    ) -> Result<Self::Ok, Self::Error> {
+        self.write_variant(variant_index, variant)
+    }
+    fn serialize_newtype_struct<T: ?Sized>(
+        self,
+        name: &'static str,
         value: &T,
     ) -> Result<Self::Ok, Self::Error>
     where
         T: Serialize,
     {
-        value.serialize(self)
+        self.string_tag = name == STRING_TAGGED;
+        let res = value.serialize(&mut *self);
+        self.string_tag = false;
+        res
     }
     fn serialize_newtype_variant<T: ?Sized>(
         self,
         _name: &'static str,
-        _variant_index: u32,
-        _variant: &'static str,
+        variant_index: u32,
+        variant: &'static str,
         value: &T,
     ) -> Result<Self::Ok, Self::Error>
     where
         T: Serialize,
     {
+        self.write_variant(variant_index, variant)?;
         value.serialize(self)
     }
     fn serialize_seq(self, len: Option<usize>) -> Result<Self::SerializeSeq, Self::Error> {
@@ -273,6 +358,9 @@ impl<'a, 'b, W: std::io::Write> ser::Serializer for &'b mut Serializer<'a, W> {
             return Err(Self::Error::NotImplemented);
         }
         let len = len.unwrap();
+        // the length is sent with the first element, which is not the value
+        // a string tag was meant for
+        self.string_tag = false;
         Ok(SeqSerializer {
             ser: self,
             len_sent: false,
@@ -292,10 +380,11 @@ impl<'a, 'b, W: std::io::Write> ser::Serializer for &'b mut Serializer<'a, W> {
     fn serialize_tuple_variant(
         self,
         _name: &'static str,
-        _variant_index: u32,
-        _variant: &'static str,
+        variant_index: u32,
+        variant: &'static str,
         len: usize,
     ) -> Result<Self::SerializeTupleVariant, Self::Error> {
+        self.write_variant(variant_index, variant)?;
         self.serialize_seq(Some(len))
     }
     fn serialize_map(self, len: Option<usize>) -> Result<Self::SerializeMap, Self::Error> {
@@ -311,18 +400,218 @@ impl<'a, 'b, W: std::io::Write> ser::Serializer for &'b mut Serializer<'a, W> {
     fn serialize_struct_variant(
         self,
         _name: &'static str,
-        _variant_index: u32,
-        _variant: &'static str,
+        variant_index: u32,
+        variant: &'static str,
         len: usize,
     ) -> Result<Self::SerializeStructVariant, Self::Error> {
+        self.write_variant(variant_index, variant)?;
         self.serialize_seq(Some(len))
     }
 }
 
+/// wire encoding with access to the negotiated protocol version
+///
+/// implemented by types whose layout differs between protocol minor versions,
+/// everything else goes through serde
+pub trait WorkerEncode {
+    fn encode<W: std::io::Write>(&self, ser: &mut Serializer<W>) -> crate::error::Result<()>;
+}
+
+macro_rules! implement_worker_encode {
+    ($($ty:ty),*) => {
+        $(
+            impl WorkerEncode for $ty {
+                fn encode<W: std::io::Write>(
+                    &self,
+                    ser: &mut Serializer<W>,
+                ) -> crate::error::Result<()> {
+                    self.serialize(ser)
+                }
+            }
+        )*
+    };
+}
+
+implement_worker_encode!(u64, bool, str, String);
+
+impl<T: WorkerEncode + ?Sized> WorkerEncode for &T {
+    fn encode<W: std::io::Write>(&self, ser: &mut Serializer<W>) -> crate::error::Result<()> {
+        (**self).encode(ser)
+    }
+}
+
+impl<T: WorkerEncode> WorkerEncode for Option<T> {
+    fn encode<W: std::io::Write>(&self, ser: &mut Serializer<W>) -> crate::error::Result<()> {
+        self.is_some().serialize(&mut *ser)?;
+        match self {
+            Some(v) => v.encode(ser),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<T: WorkerEncode> WorkerEncode for [T] {
+    fn encode<W: std::io::Write>(&self, ser: &mut Serializer<W>) -> crate::error::Result<()> {
+        self.len().serialize(&mut *ser)?;
+        self.iter().try_for_each(|v| v.encode(ser))
+    }
+}
+
+impl<T: WorkerEncode> WorkerEncode for Vec<T> {
+    fn encode<W: std::io::Write>(&self, ser: &mut Serializer<W>) -> crate::error::Result<()> {
+        self.as_slice().encode(ser)
+    }
+}
+
+impl<K: WorkerEncode, V: WorkerEncode, S> WorkerEncode for std::collections::HashMap<K, V, S> {
+    fn encode<W: std::io::Write>(&self, ser: &mut Serializer<W>) -> crate::error::Result<()> {
+        self.len().serialize(&mut *ser)?;
+        self.iter().try_for_each(|(k, v)| {
+            k.encode(ser)?;
+            v.encode(ser)
+        })
+    }
+}
+
+impl<A: WorkerEncode, B: WorkerEncode> WorkerEncode for (A, B) {
+    fn encode<W: std::io::Write>(&self, ser: &mut Serializer<W>) -> crate::error::Result<()> {
+        self.0.encode(ser)?;
+        self.1.encode(ser)
+    }
+}
+
+#[doc(hidden)]
+pub fn encode_serde<T: Serialize + ?Sized, W: std::io::Write>(
+    value: &T,
+    ser: &mut Serializer<W>,
+) -> crate::error::Result<()> {
+    value.serialize(ser)
+}
+
+#[doc(hidden)]
+pub fn encode_empty_as_none<T: std::fmt::Display, W: std::io::Write>(
+    value: &Option<T>,
+    ser: &mut Serializer<W>,
+) -> crate::error::Result<()> {
+    match value {
+        Some(v) => v.to_string().serialize(ser),
+        None => "".serialize(ser),
+    }
+}
+
+#[doc(hidden)]
+pub fn encode_store_path<W: std::io::Write>(
+    base_name: &str,
+    ser: &mut Serializer<W>,
+) -> crate::error::Result<()> {
+    format!("{}/{}", crate::consts::STORE_DIR, base_name).serialize(ser)
+}
+
+pub struct FramedWriter<'a, W> {
+    write: &'a mut W,
+    buf: Vec<u8>,
+    frame_size: usize,
+}
+
+impl<'a, W: std::io::Write> FramedWriter<'a, W> {
+    pub const DEFAULT_FRAME_SIZE: usize = 32 * 1024;
+    pub fn new(write: &'a mut W) -> Self {
+        Self::with_frame_size(write, Self::DEFAULT_FRAME_SIZE)
+    }
+    pub fn with_frame_size(write: &'a mut W, frame_size: usize) -> Self {
+        assert!(frame_size > 0, "frame size must be positive");
+        Self {
+            write,
+            buf: Vec::with_capacity(frame_size),
+            frame_size,
+        }
+    }
+    fn write_frame(&mut self) -> std::result::Result<(), std::io::Error> {
+        // an empty frame marks the end of stream, never emit one before finish
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        self.buf
+            .len()
+            .serialize(&mut Serializer::new(&mut self.write))
+            .map_err(std::io::Error::other)?;
+        self.write.write_all(&self.buf)?;
+        self.buf.clear();
+        Ok(())
+    }
+    /// write out buffered data followed by the terminating empty frame
+    pub fn finish(mut self) -> std::result::Result<(), std::io::Error> {
+        self.write_frame()?;
+        0_u64
+            .serialize(&mut Serializer::new(&mut self.write))
+            .map_err(std::io::Error::other)?;
+        self.write.flush()
+    }
+}
+
+impl<'a, W: std::io::Write> std::io::Write for FramedWriter<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> std::result::Result<usize, std::io::Error> {
+        let size = std::cmp::min(buf.len(), self.frame_size - self.buf.len());
+        self.buf.extend_from_slice(&buf[..size]);
+        if self.buf.len() == self.frame_size {
+            self.write_frame()?;
+        }
+        Ok(size)
+    }
+    fn flush(&mut self) -> std::result::Result<(), std::io::Error> {
+        self.write_frame()?;
+        self.write.flush()
+    }
+}
+
+/// serializer over a tokio [`tokio::io::AsyncWrite`]
+///
+/// values are encoded by [`Serializer`] into a buffer and written out at once,
+/// so the bytes on the wire are identical
+#[cfg(feature = "async")]
+pub struct AsyncSerializer<'a, W> {
+    write: &'a mut W,
+    version: u64,
+}
+
+#[cfg(feature = "async")]
+impl<'a, W: tokio::io::AsyncWrite + Unpin> AsyncSerializer<'a, W> {
+    pub fn new(write: &'a mut W) -> Self {
+        Self::with_version(write, PROTOCOL_VERSION)
+    }
+    pub fn with_version(write: &'a mut W, version: u64) -> Self {
+        Self { write, version }
+    }
+    /// protocol version negotiated with the peer
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+    pub async fn serialize<T: Serialize + ?Sized>(
+        &mut self,
+        value: &T,
+    ) -> crate::error::Result<()> {
+        let mut buf = vec![];
+        value.serialize(&mut Serializer::with_version(&mut buf, self.version))?;
+        self.write_all(&buf).await
+    }
+    pub async fn encode<T: WorkerEncode + ?Sized>(
+        &mut self,
+        value: &T,
+    ) -> crate::error::Result<()> {
+        let mut buf = vec![];
+        Serializer::with_version(&mut buf, self.version).encode(value)?;
+        self.write_all(&buf).await
+    }
+    async fn write_all(&mut self, buf: &[u8]) -> crate::error::Result<()> {
+        use tokio::io::AsyncWriteExt;
+        Ok(self.write.write_all(buf).await?)
+    }
+}
+
 #[test]
 fn test_u64() {
     let mut buf = vec![];
-    let mut ser = Serializer { write: &mut buf };
+    let mut ser = Serializer::new(&mut buf);
     42_u64.serialize(&mut ser).unwrap();
     assert_eq!(buf, [0x2a, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
 }
@@ -330,7 +619,7 @@ fn test_u64() {
 #[test]
 fn test_string() {
     let mut buf = vec![];
-    let mut ser = Serializer { write: &mut buf };
+    let mut ser = Serializer::new(&mut buf);
     String::from("hello").serialize(&mut ser).unwrap();
     assert_eq!(
         buf,
@@ -344,7 +633,7 @@ fn test_string() {
 #[test]
 fn test_string_seq() {
     let mut buf = vec![];
-    let mut ser = Serializer { write: &mut buf };
+    let mut ser = Serializer::new(&mut buf);
     vec![String::from("hello"); 5].serialize(&mut ser).unwrap();
     assert_eq!(
         buf,
@@ -363,7 +652,7 @@ fn test_string_seq() {
 #[test]
 fn test_none() {
     let mut buf = vec![];
-    let mut ser = Serializer { write: &mut buf };
+    let mut ser = Serializer::new(&mut buf);
     Option::<u64>::None.serialize(&mut ser).unwrap();
     assert_eq!(buf, [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
 }
@@ -371,7 +660,7 @@ fn test_none() {
 #[test]
 fn test_some() {
     let mut buf = vec![];
-    let mut ser = Serializer { write: &mut buf };
+    let mut ser = Serializer::new(&mut buf);
     Option::<u64>::Some(42).serialize(&mut ser).unwrap();
     assert_eq!(
         buf,
@@ -385,7 +674,7 @@ fn test_some() {
 #[test]
 fn test_tuple() {
     let mut buf = vec![];
-    let mut ser = Serializer { write: &mut buf };
+    let mut ser = Serializer::new(&mut buf);
     let t: (u64, u64, u64) = (1, 2, 3);
     t.serialize(&mut ser).unwrap();
     assert_eq!(
@@ -400,7 +689,7 @@ fn test_tuple() {
 #[test]
 fn test_struct() {
     let mut buf = vec![];
-    let mut ser = Serializer { write: &mut buf };
+    let mut ser = Serializer::new(&mut buf);
     #[derive(Serialize, Debug, PartialEq)]
     struct Test {
         name: String,
@@ -420,3 +709,249 @@ fn test_struct() {
         ]
     );
 }
+
+#[test]
+fn test_framed() {
+    use std::io::Write;
+    let mut buf = vec![];
+    let mut fw = FramedWriter::with_frame_size(&mut buf, 4);
+    fw.write_all(b"hello").unwrap();
+    fw.finish().unwrap();
+    assert_eq!(
+        buf,
+        [
+            0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, b'h', b'e', b'l', b'l', 0x01, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, b'o', 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00,
+        ]
+    );
+}
+
+#[test]
+fn test_framed_round_trip() {
+    use std::io::{Read, Write};
+    let data: Vec<u8> = (0..100_000_u32).map(|x| x as u8).collect();
+    let mut buf = vec![];
+    let mut fw = FramedWriter::new(&mut buf);
+    fw.write_all(&data).unwrap();
+    fw.flush().unwrap();
+    fw.write_all(&data).unwrap();
+    fw.finish().unwrap();
+    let mut read: &[u8] = &buf[..];
+    let mut out = vec![];
+    crate::de::FramedReader::new(&mut read)
+        .read_to_end(&mut out)
+        .unwrap();
+    assert_eq!(out, [&data[..], &data[..]].concat());
+    assert!(read.is_empty());
+}
+
+#[test]
+fn test_versioned() {
+    use crate::protocol::protocol_version_minor;
+    struct Test {
+        name: String,
+        num: u64, // since 1.30
+    }
+    impl WorkerEncode for Test {
+        fn encode<W: std::io::Write>(&self, ser: &mut Serializer<W>) -> crate::error::Result<()> {
+            self.name.encode(ser)?;
+            if protocol_version_minor(ser.version()) >= 30 {
+                self.num.encode(ser)?;
+            }
+            Ok(())
+        }
+    }
+    let test = Test {
+        name: String::from("hello"),
+        num: 42,
+    };
+    let mut buf = vec![];
+    test.encode(&mut Serializer::with_version(&mut buf, 1 << 8 | 29))
+        .unwrap();
+    assert_eq!(
+        buf,
+        [
+            0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, b'h', b'e', b'l', b'l', b'o', 0x00,
+            0x00, 0x00
+        ]
+    );
+    let mut buf = vec![];
+    test.encode(&mut Serializer::with_version(&mut buf, 1 << 8 | 30))
+        .unwrap();
+    assert_eq!(
+        buf,
+        [
+            0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, b'h', b'e', b'l', b'l', b'o', 0x00,
+            0x00, 0x00, 0x2a, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ]
+    );
+}
+
+#[test]
+fn test_derive() {
+    #[derive(WorkerEncode)]
+    enum Kind {
+        Foo,
+        Bar = 5,
+    }
+    #[derive(WorkerEncode)]
+    struct Test {
+        #[worker(store_path)]
+        path: String,
+        #[worker(empty_as_none)]
+        deriver: Option<String>,
+        kind: Kind,
+        #[worker(skip)]
+        _id: u64,
+        #[worker(since = 30)]
+        num: u64,
+    }
+    let test = Test {
+        path: String::from("a"),
+        deriver: None,
+        kind: Kind::Bar,
+        _id: 1,
+        num: 42,
+    };
+    let mut buf = vec![];
+    Serializer::with_version(&mut buf, 1 << 8 | 29)
+        .encode(&test)
+        .unwrap();
+    assert_eq!(
+        buf,
+        [
+            0x0c, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, b'/', b'n', b'i', b'x', b'/', b's',
+            b't', b'o', b'r', b'e', b'/', b'a', 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ]
+    );
+    let mut buf = vec![];
+    Serializer::with_version(&mut buf, 1 << 8 | 30)
+        .encode(&Kind::Foo)
+        .unwrap();
+    assert_eq!(buf, [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+}
+
+#[test]
+fn test_map() {
+    let mut buf = vec![];
+    let mut ser = Serializer::new(&mut buf);
+    let map: std::collections::BTreeMap<String, u64> =
+        [(String::from("a"), 1), (String::from("b"), 2)].into();
+    map.serialize(&mut ser).unwrap();
+    assert_eq!(
+        buf,
+        [
+            0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, b'a', 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, b'b', 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ]
+    );
+    let mut buf = vec![];
+    let mut ser = Serializer::new(&mut buf);
+    std::collections::BTreeMap::<String, u64>::new()
+        .serialize(&mut ser)
+        .unwrap();
+    assert_eq!(buf, [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+}
+
+#[test]
+fn test_enum() {
+    #[derive(Serialize)]
+    enum Test {
+        Unit,
+        Newtype(u64),
+        Struct { name: String },
+    }
+    let mut buf = vec![];
+    let mut ser = Serializer::new(&mut buf);
+    vec![
+        Test::Unit,
+        Test::Newtype(42),
+        Test::Struct {
+            name: String::from("hello"),
+        },
+    ]
+    .serialize(&mut ser)
+    .unwrap();
+    assert_eq!(
+        buf,
+        [
+            0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x2a, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x05, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, b'h', b'e', b'l', b'l', b'o', 0x00, 0x00, 0x00,
+        ]
+    );
+    let mut buf = vec![];
+    let mut ser = Serializer::new(&mut buf);
+    StringTagged(Test::Newtype(42)).serialize(&mut ser).unwrap();
+    assert_eq!(
+        buf,
+        [
+            0x07, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, b'N', b'e', b'w', b't', b'y', b'p',
+            b'e', 0x00, 0x2a, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ]
+    );
+
+    // only an enum right inside the wrapper is tagged by name
+    #[derive(Serialize)]
+    struct Outer {
+        first: Test,
+        second: Test,
+    }
+    let outer = Outer {
+        first: Test::Unit,
+        second: Test::Unit,
+    };
+    fn to_vec<T: Serialize>(value: &T) -> Vec<u8> {
+        let mut buf = vec![];
+        value.serialize(&mut Serializer::new(&mut buf)).unwrap();
+        buf
+    }
+    assert_eq!(to_vec(&StringTagged(&outer)), to_vec(&outer));
+    let units = vec![Test::Unit, Test::Unit];
+    assert_eq!(to_vec(&StringTagged(&units)), to_vec(&units));
+}
+
+#[test]
+fn test_reader() {
+    let mut buf = vec![];
+    let mut ser = Serializer::new(&mut buf);
+    ser.serialize_reader(5, &b"hello world"[..]).unwrap();
+    assert_eq!(
+        buf,
+        [
+            0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, b'h', b'e', b'l', b'l', b'o', 0x00,
+            0x00, 0x00
+        ]
+    );
+    let mut buf = vec![];
+    let mut ser = Serializer::new(&mut buf);
+    assert!(ser.serialize_reader(5, &b"hell"[..]).is_err());
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn test_async() {
+    async fn check<T: Serialize>(value: T) {
+        let mut expected = vec![];
+        value
+            .serialize(&mut Serializer::new(&mut expected))
+            .unwrap();
+        let mut buf = vec![];
+        AsyncSerializer::new(&mut buf)
+            .serialize(&value)
+            .await
+            .unwrap();
+        assert_eq!(buf, expected);
+    }
+    check(42_u64).await;
+    check(String::from("hello")).await;
+    check(vec![String::from("hello"); 5]).await;
+    check(Option::<u64>::None).await;
+    check(Option::<u64>::Some(42)).await;
+    check((1_u64, 2_u64, 3_u64)).await;
+}
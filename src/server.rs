@@ -0,0 +1,846 @@
+//! the daemon side of the worker protocol
+
+use crate::consts::{BuildMode, BuildStatus};
+use crate::de::{Deserializer, FramedReader, Limits};
+use crate::derivation::Derivation;
+use crate::error::{Error, Result};
+use crate::hash::Hash;
+use crate::protocol::*;
+use crate::ser::Serializer;
+use crate::signing::SecretKey;
+use crate::store_path::FileIngestionMethod;
+use crate::types::*;
+use kmpsearch::Haystack;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+/// path infos of everything in the store, keyed by store path
+pub type Db = RwLock<HashMap<StorePath, ValidPathInfo>>;
+
+/// location of a store path below the real store directory
+fn store_location(store: &Path, path: &StorePath) -> PathBuf {
+    store
+        .join(crate::consts::STORE_DIR.trim_start_matches('/'))
+        .join(path.base_name())
+}
+
+/// read and parse a `.drv` file, checking that it has the path it is stored at
+fn read_derivation(store: &Path, path: &StorePath) -> Result<Derivation> {
+    let name = path
+        .name()
+        .strip_suffix(".drv")
+        .ok_or_else(|| Error::Message(format!("{} is not a derivation", path)))?;
+    let aterm = std::fs::read_to_string(store_location(store, path))?;
+    let drv = Derivation::from_aterm(name, &aterm)?;
+    if &drv.store_path()? != path {
+        return Err(Error::Message(format!("{} has the wrong contents", path)));
+    }
+    Ok(drv)
+}
+
+/// hash modulo of a derivation in the store and, recursively, its inputs
+fn derivation_hash(
+    store: &Path,
+    path: &StorePath,
+    hashes: &mut HashMap<StorePath, Hash>,
+) -> Result<Hash> {
+    if let Some(hash) = hashes.get(path) {
+        return Ok(hash.clone());
+    }
+    let drv = read_derivation(store, path)?;
+    let hash = drv.hash_modulo(|x| derivation_hash(store, x, hashes))?;
+    hashes.insert(path.clone(), hash.clone());
+    Ok(hash)
+}
+
+/// check that the derivation only builds the output paths it is entitled to,
+/// fixed-output derivations are checked by their hash alone, their outputs
+/// are checked against it after the build. the others need their `.drv` file
+/// and those of their inputs in the store, and may only read the inputs the
+/// `.drv` file names
+///
+/// returns the hash that identifies the outputs in realisations
+fn check_outputs(store: &Path, drv: &BasicDerivation) -> Result<Hash> {
+    let drv_path: StorePath = drv.name.parse()?;
+    let name = drv_path
+        .name()
+        .strip_suffix(".drv")
+        .ok_or_else(|| Error::Message(format!("{} is not a derivation", drv_path)))?;
+    let full = Derivation {
+        name: name.to_string(),
+        outputs: drv.outputs.clone(),
+        input_drvs: vec![],
+        input_srcs: drv.input_srcs.clone(),
+        platform: drv.platform.clone(),
+        builder: drv.builder.clone(),
+        args: drv.args.clone(),
+        env: drv.env.clone(),
+    };
+    let (hash, expected) = if full.fixed_output()?.is_some() {
+        (
+            full.output_hash(|_| Err(Error::NotImplemented))?,
+            full.output_paths(|_| Err(Error::NotImplemented))?,
+        )
+    } else {
+        let stored = read_derivation(store, &drv_path)?;
+        // the inputs are the stored sources and the outputs of the stored
+        // input derivations, as the client would have resolved them
+        let basic = stored.to_basic(&drv_path, |input, output| {
+            read_derivation(store, input)?
+                .outputs
+                .into_iter()
+                .find(|x| x.name == output)
+                .ok_or_else(|| Error::Message(format!("{} has no output {}", input, output)))?
+                .path_s
+                .parse()
+        })?;
+        let mut input_srcs = drv.input_srcs.clone();
+        input_srcs.sort();
+        input_srcs.dedup();
+        if (
+            &basic.outputs,
+            &basic.input_srcs,
+            &basic.platform,
+            &basic.builder,
+            &basic.args,
+            &basic.env,
+        ) != (
+            &drv.outputs,
+            &input_srcs,
+            &drv.platform,
+            &drv.builder,
+            &drv.args,
+            &drv.env,
+        ) {
+            return Err(Error::Message(format!(
+                "derivation does not match {}",
+                drv_path
+            )));
+        }
+        let mut hashes = HashMap::new();
+        (
+            stored.output_hash(|x| derivation_hash(store, x, &mut hashes))?,
+            stored.output_paths(|x| derivation_hash(store, x, &mut hashes))?,
+        )
+    };
+    for (out, (name, path)) in drv.outputs.iter().zip(&expected) {
+        if out.name != *name || out.path_s != path.to_string() {
+            return Err(Error::Message(format!(
+                "output {} of {} should be {}",
+                out.name, drv_path, path
+            )));
+        }
+    }
+    Ok(hash)
+}
+
+/// seconds since the unix epoch
+fn unix_time() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |x| x.as_secs())
+}
+
+/// hash a built output the way its content address was hashed, `nar` being
+/// its serialisation
+fn content_hash(location: &Path, nar: &[u8], ca: &ContentAddress) -> Result<Hash> {
+    let method = match ca {
+        ContentAddress::Text(_) => FileIngestionMethod::Flat,
+        ContentAddress::Fixed { method, .. } => *method,
+    };
+    let algo = ca.hash().algo();
+    match method {
+        FileIngestionMethod::Flat if !std::fs::symlink_metadata(location)?.is_file() => Err(
+            Error::Message(format!("{} is not a regular file", location.display())),
+        ),
+        FileIngestionMethod::Flat => Ok(Hash::compute(algo, &std::fs::read(location)?)),
+        FileIngestionMethod::Recursive => Ok(Hash::compute(algo, nar)),
+    }
+}
+
+/// run the builder in a sandbox and register its outputs, fixed outputs only
+/// if they have the declared hash
+fn build(
+    store: &Path,
+    db: &Db,
+    bwrap: &str,
+    sh: &str,
+    key: Option<&SecretKey>,
+    drv: BasicDerivation,
+) -> Result<BuildResult> {
+    let env_overrride: std::collections::HashMap<String, String> = drv
+        .outputs
+        .iter()
+        .map(|x| (x.name.clone(), x.path_s.clone()))
+        .collect();
+    let tmp_store = tempdir::TempDir::new("sirius")?;
+    let mut binds: Vec<String> = vec![];
+    for x in &drv.input_srcs {
+        binds.extend([
+            "--ro-bind".to_string(),
+            store_location(store, x).to_str().unwrap().to_string(),
+            x.to_string(),
+        ]);
+    }
+    let mut cmd = std::process::Command::new(bwrap);
+    cmd.args([
+        "--unshare-all",
+        "--die-with-parent",
+        "--bind",
+        tmp_store.path().to_str().unwrap(),
+        "/",
+        "--dev",
+        "/dev",
+        "--proc",
+        "/proc",
+        "--tmpfs",
+        "/build",
+        "--chdir",
+        "/build",
+        "--ro-bind",
+        sh,
+        "/bin/sh",
+    ])
+    .args(binds)
+    .env_clear()
+    .env("PATH", "/path-not-set")
+    .env("HOME", "/homeless-shelter")
+    .env("NIX_STORE", "/nix/store")
+    .env("NIX_BUILD_CORES", "12")
+    .env("NIX_BUILD_TOP", "/build")
+    .env("TMPDIR", "/build")
+    .env("TEMPDIR", "/build")
+    .env("TMP", "/build")
+    .env("TEMP", "/build")
+    .envs(drv.env)
+    .envs(env_overrride)
+    .arg(drv.builder)
+    .args(drv.args);
+    let status = cmd.status()?;
+    if status.success() {
+        let refs = drv
+            .input_srcs
+            .iter()
+            .map(|x| (x.hash_part().as_bytes(), x))
+            .collect::<Vec<(&[u8], &StorePath)>>();
+        // every output is checked before any is registered
+        let mut built = vec![];
+        for x in &drv.outputs {
+            let path: StorePath = x.path_s.parse()?;
+            let from_path = store_location(tmp_store.path(), &path);
+            let data = libnar::to_vec(&from_path)?;
+            let references: Vec<StorePath> = refs
+                .iter()
+                .filter(|x| data.contains_needle(x.0))
+                .map(|x| x.1.clone())
+                .collect();
+            let ca = x.content_address()?;
+            if let Some(ca) = &ca {
+                let checked = content_hash(&from_path, &data, ca).and_then(|got| {
+                    if &got != ca.hash() {
+                        return Err(Error::Message(format!(
+                            "hash mismatch in fixed-output derivation {}: specified {}, got {}",
+                            drv.name,
+                            ca.hash(),
+                            got
+                        )));
+                    }
+                    ca.check(&path, &references)
+                });
+                if let Err(e) = checked {
+                    return Ok(BuildResult::new(BuildStatus::OutputRejected, e.to_string()));
+                }
+            }
+            let info = ValidPathInfo {
+                path,
+                info: UnkeyedValidPathInfo {
+                    deriver: None,
+                    hash: Hash::sha256(&data),
+                    ca,
+                    nar_size: data.len().try_into().unwrap(),
+                    references,
+                    registration_time: 0,
+                    sigs: vec![],
+                    ultimate: true,
+                },
+            };
+            built.push((info, data));
+        }
+        for (mut info, data) in built {
+            let to_path = store_location(store, &info.path);
+            fs_extra::remove_items(&[&to_path]).map_err(|e| Error::Message(e.to_string()))?;
+            libnar::Archive::new(&*data).unpack(&to_path)?;
+            if let Some(key) = key {
+                info.sign(key)?;
+            }
+            db.write().unwrap().insert(info.path.clone(), info);
+        }
+        Ok(BuildResult::new(BuildStatus::Built, String::new()))
+    } else {
+        Ok(BuildResult::new(
+            BuildStatus::MiscFailure,
+            String::from("builder failed"),
+        ))
+    }
+}
+
+/// builds what `BuildPaths` asks for, caching the hashes modulo of the
+/// derivations it reads
+struct Realiser<'a> {
+    store: &'a Path,
+    db: &'a Db,
+    bwrap: &'a str,
+    sh: &'a str,
+    key: Option<&'a SecretKey>,
+    hashes: HashMap<StorePath, Hash>,
+}
+
+impl Realiser<'_> {
+    /// build a derivation in the store unless the `wanted` outputs are valid,
+    /// building the input derivations first
+    ///
+    /// returns the wanted outputs, the builder always makes all of them
+    fn realise(&mut self, drv_path: &StorePath, wanted: &OutputsSpec) -> Result<BuiltPath> {
+        let (store, db) = (self.store, self.db);
+        let drv = read_derivation(store, drv_path)?;
+        let hashes = &mut self.hashes;
+        let mut outputs: BTreeMap<String, StorePath> = drv
+            .output_paths(|x| derivation_hash(store, x, hashes))?
+            .into_iter()
+            .collect();
+        if let OutputsSpec::Names(names) = wanted {
+            if let Some(name) = names.iter().find(|x| !outputs.contains_key(*x)) {
+                return Err(Error::Message(format!(
+                    "{} has no output {}",
+                    drv_path, name
+                )));
+            }
+            outputs.retain(|name, _| names.contains(name));
+        }
+        let built = BuiltPath::Built {
+            drv_path: drv_path.clone(),
+            outputs,
+        };
+        if built
+            .out_paths()
+            .iter()
+            .all(|x| db.read().unwrap().contains_key(x))
+        {
+            return Ok(built);
+        }
+        let mut inputs = HashMap::new();
+        for (input, names) in &drv.input_drvs {
+            let wanted = OutputsSpec::Names(names.iter().cloned().collect());
+            inputs.insert(input, self.realise(input, &wanted)?);
+        }
+        if let Some(x) = drv
+            .input_srcs
+            .iter()
+            .find(|x| !db.read().unwrap().contains_key(x))
+        {
+            return Err(Error::Message(format!("{} is not valid", x)));
+        }
+        let basic = drv.to_basic(drv_path, |input, output| match inputs.get(input) {
+            Some(BuiltPath::Built { outputs, .. }) => outputs
+                .get(output)
+                .cloned()
+                .ok_or_else(|| Error::Message(format!("{} has no output {}", input, output))),
+            _ => Err(Error::Message(format!("{} is not an input", input))),
+        })?;
+        check_outputs(store, &basic)?;
+        let result = build(store, db, self.bwrap, self.sh, self.key, basic)?;
+        if result.status != BuildStatus::Built {
+            return Err(Error::Message(format!(
+                "building {} failed: {}",
+                drv_path, result.error_msg
+            )));
+        }
+        Ok(built)
+    }
+
+    /// make sure every path is valid, building derivation outputs as needed
+    fn build_paths(&mut self, paths: &[DerivedPath]) -> Result<()> {
+        for path in paths {
+            match path {
+                DerivedPath::Opaque(path) => {
+                    if !self.db.read().unwrap().contains_key(path) {
+                        return Err(Error::Message(format!("{} is not valid", path)));
+                    }
+                }
+                DerivedPath::Built { drv_path, outputs } => {
+                    self.realise(drv_path, outputs)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// end the turn with an error instead of `STDERR_LAST` and a result, the
+/// client may go on with the next operation
+fn write_error<W: std::io::Write>(ser: &mut Serializer<W>, version: u64, msg: &str) -> Result<()> {
+    STDERR_ERROR.serialize(&mut *ser)?;
+    if protocol_version_minor(version) >= 26 {
+        "Error".serialize(&mut *ser)?;
+        0_u64.serialize(&mut *ser)?; // verbosity, lvlError
+        "Error".serialize(&mut *ser)?; // obsolete name
+        msg.serialize(&mut *ser)?;
+        0_u64.serialize(&mut *ser)?; // no position
+        0_u64.serialize(&mut *ser)?; // no traces
+    } else {
+        msg.serialize(&mut *ser)?;
+        1_u64.serialize(&mut *ser)?; // exit status
+    }
+    Ok(())
+}
+
+/// serve one client connection until it hangs up, signing what gets built
+/// with `key` if there is one
+pub fn handle<R: std::io::Read, W: std::io::Write>(
+    mut read: R,
+    mut write: W,
+    store: &Path,
+    db: &Db,
+    bwrap: &str,
+    sh: &str,
+    key: Option<&SecretKey>,
+) -> Result<()> {
+    let version = {
+        let mut ser = Serializer::new(&mut write);
+        let mut des = Deserializer::new(&mut read);
+        if u64::deserialize(&mut des)? != WORKER_MAGIC_1 {
+            return Err(Error::Message("protocol magic mismatch".to_string()));
+        }
+        WORKER_MAGIC_2.serialize(&mut ser)?;
+        PROTOCOL_VERSION.serialize(&mut ser)?;
+        write.flush()?;
+        let client_version = u64::deserialize(&mut des)?;
+        if protocol_version_major(client_version) != protocol_version_major(PROTOCOL_VERSION) {
+            return Err(Error::Message(
+                "protocol major version mismatch".to_string(),
+            ));
+        }
+        if protocol_version_minor(client_version) < protocol_version_minor(MIN_PROTOCOL_VERSION) {
+            return Err(Error::Message("protocol minor version too low".to_string()));
+        }
+        let version = std::cmp::min(client_version, PROTOCOL_VERSION);
+        let mut ser = Serializer::with_version(&mut write, version);
+        let mut des = Deserializer::with_version(&mut read, version);
+        if protocol_version_minor(version) >= 14 {
+            Option::<u64>::deserialize(&mut des)?; // obsolete CPU affinity
+        }
+        if protocol_version_minor(version) >= 11 {
+            bool::deserialize(&mut des)?; // obsolete reserve space
+        }
+        if protocol_version_minor(version) >= 33 {
+            concat!("sirius ", env!("CARGO_PKG_VERSION")).serialize(&mut ser)?;
+        }
+        if protocol_version_minor(version) >= 35 {
+            0_u64.serialize(&mut ser)?; // trusted flag, unknown
+        }
+        STDERR_LAST.serialize(&mut ser)?;
+        write.flush()?;
+        version
+    };
+    loop {
+        let mut des = Deserializer::with_version(&mut read, version).with_limits(Limits::SERVER);
+        let mut ser = Serializer::with_version(&mut write, version);
+        let op = match Op::deserialize(&mut des) {
+            Ok(op) => op,
+            Err(Error::IO(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        match op {
+            Op::Nop => (),
+            Op::SetOptions => {
+                ClientSettings::deserialize(&mut des)?;
+                STDERR_LAST.serialize(&mut ser)?;
+            }
+            Op::QueryPathInfo => {
+                let path = StorePath::deserialize(&mut des)?;
+                STDERR_LAST.serialize(&mut ser)?;
+                let db = db.read().unwrap();
+                ser.encode(&db.get(&path).map(|x| &x.info))?;
+            }
+            Op::QueryValidPaths => {
+                let paths = Vec::<StorePath>::deserialize(&mut des)?;
+                if protocol_version_minor(version) >= 27 {
+                    bool::deserialize(&mut des)?; // substitute
+                }
+                STDERR_LAST.serialize(&mut ser)?;
+                let db = &db.read().unwrap();
+                db.iter()
+                    .filter(|x| paths.contains(x.0))
+                    .map(|x| x.0.clone())
+                    .collect::<Vec<StorePath>>()
+                    .serialize(&mut ser)?;
+            }
+            Op::AddMultipleToStore => {
+                bool::deserialize(&mut des)?;
+                bool::deserialize(&mut des)?;
+                let mut fr = FramedReader::new(&mut read);
+                let num_paths = u64::deserialize(
+                    &mut Deserializer::with_version(&mut fr, version).with_limits(Limits::SERVER),
+                )?;
+                for _i in 0..num_paths {
+                    let path = Deserializer::with_version(&mut fr, version)
+                        .with_limits(Limits::SERVER)
+                        .decode::<ValidPathInfo>()?;
+                    if let Some(ca) = &path.info.ca {
+                        ca.check(&path.path, &path.info.references)?;
+                    }
+                    let mut nar = libnar::Archive::new(&mut fr);
+                    nar.unpack(store_location(store, &path.path))?;
+                    db.write().unwrap().insert(path.path.clone(), path);
+                }
+                STDERR_LAST.serialize(&mut ser)?;
+            }
+            Op::BuildDerivation => {
+                let drv = BasicDerivation::deserialize(&mut des)?;
+                u64::deserialize(&mut des)?;
+                let result = match check_outputs(store, &drv) {
+                    Ok(drv_hash) => {
+                        let outputs = drv
+                            .outputs
+                            .iter()
+                            .map(|x| Ok((x.name.clone(), x.path_s.parse()?)))
+                            .collect::<Result<Vec<(String, StorePath)>>>()?;
+                        let start_time = unix_time();
+                        let mut result = build(store, db, bwrap, sh, key, drv)?;
+                        result.times_built = 1;
+                        result.start_time = start_time;
+                        result.stop_time = unix_time();
+                        if result.status == BuildStatus::Built {
+                            for (name, out_path) in outputs {
+                                let id = DrvOutput {
+                                    drv_hash: drv_hash.clone(),
+                                    output_name: name.clone(),
+                                };
+                                result.built_outputs.insert(
+                                    name,
+                                    Realisation {
+                                        id,
+                                        out_path,
+                                        signature: vec![],
+                                        dependent_realisations: HashMap::new(),
+                                    },
+                                );
+                            }
+                        }
+                        result
+                    }
+                    Err(e) => BuildResult::new(BuildStatus::InputRejected, e.to_string()),
+                };
+                STDERR_LAST.serialize(&mut ser)?;
+                ser.encode(&result)?;
+            }
+            Op::BuildPaths => {
+                let paths = des.decode::<Vec<DerivedPath>>()?;
+                let mode = if protocol_version_minor(version) >= 15 {
+                    BuildMode::deserialize(&mut des)?
+                } else {
+                    BuildMode::Normal
+                };
+                let built = match mode {
+                    BuildMode::Normal => Realiser {
+                        store,
+                        db,
+                        bwrap,
+                        sh,
+                        key,
+                        hashes: HashMap::new(),
+                    }
+                    .build_paths(&paths),
+                    _ => Err(Error::Unsupported("repairing or checking builds")),
+                };
+                match built {
+                    Ok(()) => {
+                        STDERR_LAST.serialize(&mut ser)?;
+                        1_u64.serialize(&mut ser)?;
+                    }
+                    Err(e) => write_error(&mut ser, version, &e.to_string())?,
+                }
+            }
+            Op::NarFromPath => {
+                let path = StorePath::deserialize(&mut des)?;
+                let location = store_location(store, &path);
+                STDERR_LAST.serialize(&mut ser)?;
+                libnar::to_writer(&mut write, location)?
+            }
+            _ => return Err(Error::NotImplemented),
+        }
+        // end of our turn, the client waits for STDERR_LAST and the result
+        write.flush()?;
+    }
+}
+
+#[cfg(test)]
+fn client_hello(ser: &mut Serializer<Vec<u8>>) {
+    WORKER_MAGIC_1.serialize(&mut *ser).unwrap();
+    PROTOCOL_VERSION.serialize(&mut *ser).unwrap();
+    Some(0_u64).serialize(&mut *ser).unwrap();
+    false.serialize(&mut *ser).unwrap();
+}
+
+#[test]
+fn test_handle() {
+    let mut input = vec![];
+    let mut ser = Serializer::new(&mut input);
+    client_hello(&mut ser);
+    Op::Nop.serialize(&mut ser).unwrap();
+    Op::QueryPathInfo.serialize(&mut ser).unwrap();
+    "/nix/store/00000000000000000000000000000000-foo"
+        .serialize(&mut ser)
+        .unwrap();
+    let mut output = vec![];
+    let db = Db::default();
+    handle(
+        &input[..],
+        &mut output,
+        Path::new("/var/empty"),
+        &db,
+        "",
+        "",
+        None,
+    )
+    .unwrap();
+
+    let mut des = Deserializer::from_slice_with_version(&output, PROTOCOL_VERSION);
+    assert_eq!(WORKER_MAGIC_2, u64::deserialize(&mut des).unwrap());
+    assert_eq!(PROTOCOL_VERSION, u64::deserialize(&mut des).unwrap());
+    String::deserialize(&mut des).unwrap();
+    assert_eq!(0, u64::deserialize(&mut des).unwrap());
+    assert_eq!(STDERR_LAST, u64::deserialize(&mut des).unwrap());
+    assert_eq!(STDERR_LAST, u64::deserialize(&mut des).unwrap());
+    assert!(!bool::deserialize(&mut des).unwrap());
+    assert!(des.remaining().is_empty());
+}
+
+#[test]
+fn test_handle_errors() {
+    let db = Db::default();
+    let store = Path::new("/var/empty");
+
+    let mut output = vec![];
+    assert!(handle(&[0; 8][..], &mut output, store, &db, "", "", None).is_err());
+    assert!(output.is_empty());
+
+    let mut input = vec![];
+    let mut ser = Serializer::new(&mut input);
+    client_hello(&mut ser);
+    Op::NarFromPath.serialize(&mut ser).unwrap();
+    "/nix/store/../../etc/passwd".serialize(&mut ser).unwrap();
+    let err = handle(&input[..], vec![], store, &db, "", "", None).unwrap_err();
+    assert!(matches!(err, Error::Message(_)));
+
+    let mut input = vec![];
+    let mut ser = Serializer::new(&mut input);
+    client_hello(&mut ser);
+    Op::AddSignatures.serialize(&mut ser).unwrap();
+    let err = handle(&input[..], vec![], store, &db, "", "", None).unwrap_err();
+    assert!(matches!(err, Error::NotImplemented));
+}
+
+#[test]
+fn test_check_outputs() {
+    use crate::derivation::{BAR_DRV, FOO_DRV};
+    let store = tempdir::TempDir::new("sirius").unwrap();
+    let bar = Derivation::from_aterm("bar", BAR_DRV).unwrap();
+    let foo = Derivation::from_aterm("foo", FOO_DRV).unwrap();
+    let foo_path = foo.store_path().unwrap();
+    let basic = foo
+        .to_basic(&foo_path, |_, _| bar.outputs[0].path_s.parse())
+        .unwrap();
+    let bar_basic = bar
+        .to_basic(&bar.store_path().unwrap(), |_, _| unreachable!())
+        .unwrap();
+
+    // fixed-output derivations need nothing from the store
+    check_outputs(store.path(), &bar_basic).unwrap();
+    let mut wrong = bar_basic;
+    wrong.outputs[0].path_s = foo.outputs[0].path_s.clone();
+    assert!(check_outputs(store.path(), &wrong).is_err());
+
+    assert!(check_outputs(store.path(), &basic).is_err());
+    std::fs::create_dir_all(store.path().join("nix/store")).unwrap();
+    for drv in [&bar, &foo] {
+        let path = drv.store_path().unwrap();
+        std::fs::write(store_location(store.path(), &path), drv.to_aterm()).unwrap();
+    }
+    check_outputs(store.path(), &basic).unwrap();
+
+    let mut wrong = basic.clone();
+    wrong.outputs[0].path_s = bar.outputs[0].path_s.clone();
+    wrong.env[3].1 = bar.outputs[0].path_s.clone();
+    assert!(check_outputs(store.path(), &wrong).is_err());
+    let mut wrong = basic.clone();
+    wrong.builder = String::from("/bin/sh");
+    assert!(check_outputs(store.path(), &wrong).is_err());
+    let mut wrong = basic.clone();
+    wrong.input_srcs.push(foo_path.clone());
+    assert!(check_outputs(store.path(), &wrong).is_err());
+    let mut reordered = basic.clone();
+    reordered.input_srcs.reverse();
+    check_outputs(store.path(), &reordered).unwrap();
+
+    // a .drv file that does not hash to its path
+    std::fs::write(
+        store_location(store.path(), &foo_path),
+        foo.to_aterm().replace("\":\"", "\"/bin/sh\""),
+    )
+    .unwrap();
+    assert!(check_outputs(store.path(), &basic).is_err());
+}
+
+#[test]
+fn test_content_hash() {
+    let dir = tempdir::TempDir::new("sirius").unwrap();
+    let file = dir.path().join("file");
+    std::fs::write(&file, b"abc").unwrap();
+    let sha1 = Hash::compute(crate::hash::HashAlgo::Sha1, b"abc");
+    let flat = ContentAddress::Fixed {
+        method: FileIngestionMethod::Flat,
+        hash: sha1.clone(),
+    };
+    assert_eq!(content_hash(&file, b"nar", &flat).unwrap(), sha1);
+    // a directory can not be hashed flat
+    assert!(content_hash(dir.path(), b"nar", &flat).is_err());
+
+    let recursive = ContentAddress::Fixed {
+        method: FileIngestionMethod::Recursive,
+        hash: Hash::sha256(b""),
+    };
+    assert_eq!(
+        content_hash(dir.path(), b"nar", &recursive).unwrap(),
+        Hash::sha256(b"nar")
+    );
+}
+
+#[test]
+fn test_build_paths() {
+    use crate::derivation::{BAR_DRV, FOO_DRV};
+    let store = tempdir::TempDir::new("sirius").unwrap();
+    std::fs::create_dir_all(store.path().join("nix/store")).unwrap();
+    let bar = Derivation::from_aterm("bar", BAR_DRV).unwrap();
+    let foo = Derivation::from_aterm("foo", FOO_DRV).unwrap();
+    for drv in [&bar, &foo] {
+        let path = drv.store_path().unwrap();
+        std::fs::write(store_location(store.path(), &path), drv.to_aterm()).unwrap();
+    }
+    // the reply to a BuildPaths, the message if it failed
+    let request = |paths: &[DerivedPath], mode: BuildMode, db: &Db| {
+        let mut input = vec![];
+        let mut ser = Serializer::new(&mut input);
+        client_hello(&mut ser);
+        Op::BuildPaths.serialize(&mut ser).unwrap();
+        ser.encode(paths).unwrap();
+        mode.serialize(&mut ser).unwrap();
+        let mut output = vec![];
+        handle(&input[..], &mut output, store.path(), db, "", "", None).unwrap();
+        let mut des = Deserializer::from_slice_with_version(&output, PROTOCOL_VERSION);
+        u64::deserialize(&mut des).unwrap();
+        u64::deserialize(&mut des).unwrap();
+        String::deserialize(&mut des).unwrap();
+        u64::deserialize(&mut des).unwrap();
+        assert_eq!(STDERR_LAST, u64::deserialize(&mut des).unwrap());
+        let result = match u64::deserialize(&mut des).unwrap() {
+            STDERR_LAST => {
+                assert_eq!(1, u64::deserialize(&mut des).unwrap());
+                Ok(())
+            }
+            STDERR_ERROR => {
+                assert_eq!("Error", String::deserialize(&mut des).unwrap());
+                assert_eq!(0, u64::deserialize(&mut des).unwrap());
+                assert_eq!("Error", String::deserialize(&mut des).unwrap());
+                let msg = String::deserialize(&mut des).unwrap();
+                assert_eq!(0, u64::deserialize(&mut des).unwrap());
+                assert_eq!(0, u64::deserialize(&mut des).unwrap());
+                Err(msg)
+            }
+            x => panic!("unexpected message {:x}", x),
+        };
+        assert!(des.remaining().is_empty());
+        result
+    };
+
+    // outputs that are valid are not built again
+    let db = Db::default();
+    let out: StorePath = foo.outputs[0].path_s.parse().unwrap();
+    let info = UnkeyedValidPathInfo {
+        deriver: None,
+        hash: Hash::sha256(b""),
+        references: vec![],
+        registration_time: 0,
+        nar_size: 0,
+        ultimate: false,
+        sigs: vec![],
+        ca: None,
+    };
+    let valid = |path: &StorePath| ValidPathInfo {
+        path: path.clone(),
+        info: info.clone(),
+    };
+    db.write().unwrap().insert(out.clone(), valid(&out));
+    let built = |outputs: &str| DerivedPath::Built {
+        drv_path: foo.store_path().unwrap(),
+        outputs: outputs.parse().unwrap(),
+    };
+    let paths = [built("*"), built("out"), DerivedPath::Opaque(out.clone())];
+    request(&paths, BuildMode::Normal, &db).unwrap();
+    assert_eq!(db.read().unwrap().len(), 1);
+
+    let opaque = DerivedPath::Opaque(foo.store_path().unwrap());
+    let msg = request(&[opaque], BuildMode::Normal, &db).unwrap_err();
+    assert!(msg.contains("is not valid"), "{}", msg);
+    let msg = request(&[built("dev")], BuildMode::Normal, &db).unwrap_err();
+    assert!(msg.contains("has no output dev"), "{}", msg);
+    let msg = request(&[built("out")], BuildMode::Check, &db).unwrap_err();
+    assert!(msg.contains("not supported"), "{}", msg);
+    // building bar needs bwrap
+    let db = Db::default();
+    assert!(request(&[built("out")], BuildMode::Normal, &db).is_err());
+    assert!(db.read().unwrap().is_empty());
+}
+
+#[test]
+fn test_build_rejected() {
+    use crate::derivation::FOO_DRV;
+    let foo = Derivation::from_aterm("foo", FOO_DRV).unwrap();
+    let basic = foo
+        .to_basic(&foo.store_path().unwrap(), |_, _| foo.env[0].1.parse())
+        .unwrap();
+    let mut input = vec![];
+    let mut ser = Serializer::new(&mut input);
+    client_hello(&mut ser);
+    Op::BuildDerivation.serialize(&mut ser).unwrap();
+    basic.serialize(&mut ser).unwrap();
+    0_u64.serialize(&mut ser).unwrap();
+    let mut output = vec![];
+    let db = Db::default();
+    handle(
+        &input[..],
+        &mut output,
+        Path::new("/var/empty"),
+        &db,
+        "",
+        "",
+        None,
+    )
+    .unwrap();
+
+    let mut des = Deserializer::from_slice_with_version(&output, PROTOCOL_VERSION);
+    u64::deserialize(&mut des).unwrap();
+    u64::deserialize(&mut des).unwrap();
+    String::deserialize(&mut des).unwrap();
+    u64::deserialize(&mut des).unwrap();
+    assert_eq!(STDERR_LAST, u64::deserialize(&mut des).unwrap());
+    assert_eq!(STDERR_LAST, u64::deserialize(&mut des).unwrap());
+    let result = des.decode::<BuildResult>().unwrap();
+    assert_eq!(result.status, BuildStatus::InputRejected);
+    assert!(!result.error_msg.is_empty());
+    assert!(result.built_outputs.is_empty());
+    assert!(des.remaining().is_empty());
+    assert!(db.read().unwrap().is_empty());
+}
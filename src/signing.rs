@@ -0,0 +1,255 @@
+//! ed25519 signatures on path infos, like `nix store sign` makes them
+//!
+//! keys and signatures are `{name}:{base64}`, the name telling which key
+//! made a signature
+//!
+//! adapated from <https://github.com/NixOS/nix/blob/master/src/libstore/path-info.cc>
+//! and <https://github.com/NixOS/nix/blob/master/src/libutil/signature/local-keys.cc>
+
+use crate::error::{Error, Result};
+use crate::hash::{Hash, HashAlgo, HashFormat};
+use crate::store_path::StorePath;
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+
+/// what gets signed, `1;{path};{nar hash};{nar size};{references}` with the
+/// references sorted and separated by commas
+pub fn fingerprint(
+    path: &StorePath,
+    nar_hash: &Hash,
+    nar_size: u64,
+    references: &[StorePath],
+) -> Result<String> {
+    if nar_hash.algo() != HashAlgo::Sha256 {
+        return Err(Error::Message(format!(
+            "nar hash of {} is not sha256",
+            path
+        )));
+    }
+    let mut references: Vec<String> = references.iter().map(|x| x.to_string()).collect();
+    references.sort();
+    Ok(format!(
+        "1;{};{};{};{}",
+        path,
+        nar_hash.to_prefixed(HashFormat::Nix32),
+        nar_size,
+        references.join(",")
+    ))
+}
+
+/// split `{name}:{base64}` and decode the key or signature
+fn split_key(s: &str, len: usize) -> Result<(&str, Vec<u8>)> {
+    let (name, data) = match s.split_once(':') {
+        Some((name, data)) if !name.is_empty() => (name, data),
+        _ => {
+            return Err(Error::InvalidKey {
+                name: String::new(),
+                reason: "missing name",
+            })
+        }
+    };
+    let invalid = |reason| Error::InvalidKey {
+        name: name.to_string(),
+        reason,
+    };
+    let data = base64::decode(data).map_err(|_| invalid("invalid base64"))?;
+    if data.len() != len {
+        return Err(invalid("wrong length"));
+    }
+    Ok((name, data))
+}
+
+/// a secret key, the file holds the seed followed by the public key
+pub struct SecretKey {
+    name: String,
+    key: SigningKey,
+}
+
+impl SecretKey {
+    /// read a key file as written by `nix key generate-secret`
+    pub fn read(path: &std::path::Path) -> Result<Self> {
+        std::fs::read_to_string(path)?.trim().parse()
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    /// `{name}:{base64}` signature of `data`
+    pub fn sign(&self, data: &str) -> String {
+        format!(
+            "{}:{}",
+            self.name,
+            base64::encode(self.key.sign(data.as_bytes()).to_bytes())
+        )
+    }
+    pub fn to_public_key(&self) -> PublicKey {
+        PublicKey {
+            name: self.name.clone(),
+            key: self.key.verifying_key(),
+        }
+    }
+}
+
+impl std::str::FromStr for SecretKey {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let (name, data) = split_key(s, 64)?;
+        let key = SigningKey::from_keypair_bytes(data[..].try_into().unwrap()).map_err(|_| {
+            Error::InvalidKey {
+                name: name.to_string(),
+                reason: "public key does not match",
+            }
+        })?;
+        Ok(SecretKey {
+            name: name.to_string(),
+            key,
+        })
+    }
+}
+
+/// no Display, so that secret keys do not end up in logs
+impl std::fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("SecretKey")
+            .field("name", &self.name)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PublicKey {
+    name: String,
+    key: VerifyingKey,
+}
+
+impl PublicKey {
+    /// read a key file as written by `nix key convert-secret-to-public`
+    pub fn read(path: &std::path::Path) -> Result<Self> {
+        std::fs::read_to_string(path)?.trim().parse()
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    /// whether `signature` is a valid signature of `data` by this key,
+    /// signatures by other keys are not
+    pub fn verify(&self, data: &str, signature: &str) -> bool {
+        match split_key(signature, 64) {
+            Ok((name, sig)) if name == self.name => {
+                let sig = Signature::from_bytes(sig[..].try_into().unwrap());
+                self.key.verify_strict(data.as_bytes(), &sig).is_ok()
+            }
+            _ => false,
+        }
+    }
+}
+
+impl std::str::FromStr for PublicKey {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let (name, data) = split_key(s, 32)?;
+        let key = VerifyingKey::from_bytes(data[..].try_into().unwrap()).map_err(|_| {
+            Error::InvalidKey {
+                name: name.to_string(),
+                reason: "not a curve point",
+            }
+        })?;
+        Ok(PublicKey {
+            name: name.to_string(),
+            key,
+        })
+    }
+}
+
+impl std::fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}:{}", self.name, base64::encode(self.key.as_bytes()))
+    }
+}
+
+/// the number of `signatures` of `data` that one of `keys` made
+pub fn count_valid(keys: &[PublicKey], data: &str, signatures: &[String]) -> usize {
+    signatures
+        .iter()
+        .filter(|sig| keys.iter().any(|key| key.verify(data, sig)))
+        .count()
+}
+
+#[test]
+fn test_fingerprint() {
+    let path: StorePath = "/nix/store/pa10z4ngm0g83kx9mssrqzz30s84vq7k-hello-2.12.1.tar.gz"
+        .parse()
+        .unwrap();
+    let hash: Hash = "sha256-jZkUKv2SV28wsM18tCqNxoCZmLxdYH2Idh9RLibH2yA="
+        .parse()
+        .unwrap();
+    let glibc: StorePath = "/nix/store/9lkz5r8b5srq6z3x5nzi1k8d5mgr4z16-glibc-2.35"
+        .parse()
+        .unwrap();
+    assert_eq!(
+        fingerprint(&path, &hash, 1009, &[]).unwrap(),
+        "1;/nix/store/pa10z4ngm0g83kx9mssrqzz30s84vq7k-hello-2.12.1.tar.gz;\
+         sha256:086vqwk2wl8zfs47sq2xpjc9k066ilmb8z6dn0q6ymwjzlm196cd;1009;"
+    );
+    assert_eq!(
+        fingerprint(&glibc, &hash, 1, &[path.clone(), glibc.clone()]).unwrap(),
+        "1;/nix/store/9lkz5r8b5srq6z3x5nzi1k8d5mgr4z16-glibc-2.35;\
+         sha256:086vqwk2wl8zfs47sq2xpjc9k066ilmb8z6dn0q6ymwjzlm196cd;1;\
+         /nix/store/9lkz5r8b5srq6z3x5nzi1k8d5mgr4z16-glibc-2.35,\
+         /nix/store/pa10z4ngm0g83kx9mssrqzz30s84vq7k-hello-2.12.1.tar.gz"
+    );
+    let sha1 = Hash::from_digest(HashAlgo::Sha1, &[0; 20]).unwrap();
+    assert!(fingerprint(&path, &sha1, 1, &[]).is_err());
+}
+
+#[test]
+fn test_sign() {
+    // the RFC 8032 test key 1
+    let secret: SecretKey = "test-1:nWGxne/9WmC6hEr0kuwsxERJxWl7MmkZcDusAxyuf2DXWpgBgrEKt9VL/tPJZAc6DuFy89qmIyWvAhpo9wdRGg=="
+        .parse()
+        .unwrap();
+    let public = secret.to_public_key();
+    assert_eq!(
+        public.to_string(),
+        "test-1:11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo="
+    );
+    assert_eq!(public.to_string().parse::<PublicKey>().unwrap(), public);
+    // the RFC's signature of the empty message
+    assert_eq!(
+        secret.sign(""),
+        "test-1:5VZDAMNgrHKQhuLMgG6CioSHfx645dl02HPgZSJJAVVfuIIVkKM7rMYeOXAc+bRr0lv18FlbviRlUUFDjnoQCw=="
+    );
+
+    let data = "1;/nix/store/pa10z4ngm0g83kx9mssrqzz30s84vq7k-hello-2.12.1.tar.gz;\
+                sha256:086vqwk2wl8zfs47sq2xpjc9k066ilmb8z6dn0q6ymwjzlm196cd;1009;";
+    let sig = secret.sign(data);
+    assert!(public.verify(data, &sig));
+    assert!(!public.verify(&data.replace("1009", "1010"), &sig));
+    assert!(!public.verify(data, &sig.replace("test-1:", "test-2:")));
+    assert!(!public.verify(data, "test-1:not base64"));
+    let other: PublicKey = "cache.nixos.org-1:6NCHdD59X431o0gWypbMrAURkbJ16ZPMQFGspcDShjY="
+        .parse()
+        .unwrap();
+    assert!(!other.verify(data, &sig));
+    assert_eq!(
+        count_valid(
+            &[other, public],
+            data,
+            &[sig.clone(), String::from("foo:bar"), sig]
+        ),
+        2
+    );
+
+    for (key, reason) in [
+        ("nWGxne/9WmC6hEr0kuwsxERJxWl7MmkZcDusAxyuf2A=", "missing name"),
+        (":11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo=", "missing name"),
+        ("test-1:!!", "invalid base64"),
+        ("test-1:11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo=", "wrong length"),
+        (
+            "test-1:nWGxne/9WmC6hEr0kuwsxERJxWl7MmkZcDusAxyuf2AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA==",
+            "public key does not match",
+        ),
+    ] {
+        match key.parse::<SecretKey>() {
+            Err(Error::InvalidKey { reason: r, .. }) => assert_eq!(r, reason, "{}", key),
+            res => panic!("{}: {:?}", key, res),
+        }
+    }
+}
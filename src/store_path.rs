@@ -0,0 +1,371 @@
+//! store paths and how nix computes them
+//!
+//! adapated from <https://github.com/NixOS/nix/blob/master/src/libstore/store-api.cc>
+//! and <https://github.com/NixOS/nix/blob/master/src/libstore/path.cc>
+
+use crate::de::WorkerDecode;
+use crate::error::Error;
+use crate::hash::{Hash, HashAlgo, HashFormat};
+use crate::ser::WorkerEncode;
+use serde::{Deserialize, Serialize};
+
+/// a validated store path, `{STORE_DIR}/{hash_part}-{name}`
+///
+/// encoded as the full path on the wire
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, std::hash::Hash)]
+pub struct StorePath {
+    base_name: String,
+}
+
+impl StorePath {
+    pub const HASH_PART_LEN: usize = 32;
+    pub const MAX_NAME_LEN: usize = 211;
+
+    /// parse the part of a store path after the store dir
+    pub fn from_base_name(base_name: &str) -> crate::error::Result<Self> {
+        Self::check_base_name(base_name).map_err(|reason| Error::InvalidStorePath {
+            path: base_name.to_string(),
+            reason,
+        })?;
+        Ok(Self {
+            base_name: base_name.to_string(),
+        })
+    }
+    fn check_base_name(base_name: &str) -> std::result::Result<(), &'static str> {
+        let bytes = base_name.as_bytes();
+        if bytes.len() < Self::HASH_PART_LEN + 2 {
+            return Err("too short");
+        }
+        if !bytes[..Self::HASH_PART_LEN]
+            .iter()
+            .all(|x| crate::hash::NIX_BASE32_CHARS.contains(x))
+        {
+            return Err("hash part is not nix base32");
+        }
+        if bytes[Self::HASH_PART_LEN] != b'-' {
+            return Err("no '-' after the hash part");
+        }
+        let name = &base_name[Self::HASH_PART_LEN + 1..];
+        if name.len() > Self::MAX_NAME_LEN {
+            return Err("name is too long");
+        }
+        if !name
+            .bytes()
+            .all(|x| x.is_ascii_alphanumeric() || b"+-._?=".contains(&x))
+        {
+            return Err("name contains a forbidden character");
+        }
+        if name == "." || name == ".." || name.starts_with(".-") || name.starts_with("..-") {
+            return Err("name is reserved");
+        }
+        Ok(())
+    }
+    /// the path without the store dir
+    pub fn base_name(&self) -> &str {
+        &self.base_name
+    }
+    /// the nix base32 encoded hash that makes the path unique
+    pub fn hash_part(&self) -> &str {
+        &self.base_name[..Self::HASH_PART_LEN]
+    }
+    pub fn name(&self) -> &str {
+        &self.base_name[Self::HASH_PART_LEN + 1..]
+    }
+    /// whether this is a `.drv` file
+    pub fn is_derivation(&self) -> bool {
+        self.name().ends_with(".drv")
+    }
+}
+
+impl std::str::FromStr for StorePath {
+    type Err = Error;
+    fn from_str(path: &str) -> crate::error::Result<Self> {
+        let base_name = path
+            .strip_prefix(crate::consts::STORE_DIR)
+            .and_then(|x| x.strip_prefix('/'))
+            .ok_or("not in the store")
+            .and_then(|x| Self::check_base_name(x).map(|_| x))
+            .map_err(|reason| Error::InvalidStorePath {
+                path: path.to_string(),
+                reason,
+            })?;
+        Ok(Self {
+            base_name: base_name.to_string(),
+        })
+    }
+}
+
+impl std::fmt::Display for StorePath {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}/{}", crate::consts::STORE_DIR, self.base_name)
+    }
+}
+
+impl Serialize for StorePath {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for StorePath {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+impl WorkerEncode for StorePath {
+    fn encode<W: std::io::Write>(
+        &self,
+        ser: &mut crate::ser::Serializer<W>,
+    ) -> crate::error::Result<()> {
+        self.to_string().encode(ser)
+    }
+}
+
+impl WorkerDecode for StorePath {
+    fn decode<R: std::io::Read>(de: &mut crate::de::Deserializer<R>) -> crate::error::Result<Self> {
+        de.decode::<String>()?.parse()
+    }
+}
+
+crate::de::decode_async_as_string!(StorePath);
+
+/// how the contents of a fixed-output path are hashed
+#[derive(Clone, Copy, Debug, PartialEq, Eq, std::hash::Hash)]
+pub enum FileIngestionMethod {
+    /// the hash of a single regular file
+    Flat,
+    /// the hash of the nar serialisation
+    Recursive,
+}
+
+impl StorePath {
+    /// `makeStorePath`, the path for the fingerprint
+    /// `{ty}:{algo}:{base16}:{STORE_DIR}:{name}`
+    pub fn make(ty: &str, hash: &Hash, name: &str) -> crate::error::Result<Self> {
+        let fingerprint = format!(
+            "{}:{}:{}:{}",
+            ty,
+            hash.to_prefixed(HashFormat::Base16),
+            crate::consts::STORE_DIR,
+            name
+        );
+        let digest = Hash::sha256(fingerprint.as_bytes());
+        Self::from_base_name(&format!(
+            "{}-{}",
+            crate::hash::encode_nix32(&compress_hash(digest.digest(), 20)),
+            name
+        ))
+    }
+    /// `makeOutputPath`, the path of output `id` of an input addressed
+    /// derivation, `hash` being its hash modulo
+    pub fn make_output(id: &str, hash: &Hash, name: &str) -> crate::error::Result<Self> {
+        let name = match id {
+            "out" => name.to_string(),
+            id => format!("{}-{}", name, id),
+        };
+        Self::make(&format!("output:{}", id), hash, &name)
+    }
+    /// `makeFixedOutputPath`, only recursive sha256 paths can have references
+    pub fn make_fixed_output(
+        name: &str,
+        method: FileIngestionMethod,
+        hash: &Hash,
+        references: &[StorePath],
+        self_reference: bool,
+    ) -> crate::error::Result<Self> {
+        if method == FileIngestionMethod::Recursive && hash.algo() == HashAlgo::Sha256 {
+            return Self::make(&make_type("source", references, self_reference), hash, name);
+        }
+        if !references.is_empty() || self_reference {
+            return Err(Error::Message(String::from(
+                "fixed-output paths with references must be recursive sha256",
+            )));
+        }
+        let inner = format!(
+            "fixed:out:{}{}:",
+            match method {
+                FileIngestionMethod::Flat => "",
+                FileIngestionMethod::Recursive => "r:",
+            },
+            hash.to_prefixed(HashFormat::Base16)
+        );
+        Self::make("output:out", &Hash::sha256(inner.as_bytes()), name)
+    }
+    /// `makeTextPath`, `hash` is the sha256 of the text
+    pub fn make_text(
+        name: &str,
+        hash: &Hash,
+        references: &[StorePath],
+    ) -> crate::error::Result<Self> {
+        if hash.algo() != HashAlgo::Sha256 {
+            return Err(Error::Message(String::from("text paths must use sha256")));
+        }
+        Self::make(&make_type("text", references, false), hash, name)
+    }
+}
+
+/// `ty` followed by the sorted references, as in the fingerprint of paths
+/// that may refer to other paths
+fn make_type(ty: &str, references: &[StorePath], self_reference: bool) -> String {
+    let mut references: Vec<&StorePath> = references.iter().collect();
+    references.sort();
+    let mut ty = ty.to_string();
+    for reference in references {
+        ty.push(':');
+        ty.push_str(&reference.to_string());
+    }
+    if self_reference {
+        ty.push_str(":self");
+    }
+    ty
+}
+
+/// xor `digest` down to `size` bytes
+fn compress_hash(digest: &[u8], size: usize) -> Vec<u8> {
+    let mut out = vec![0; size];
+    for (i, x) in digest.iter().enumerate() {
+        out[i % size] ^= x;
+    }
+    out
+}
+
+#[test]
+fn test_store_path_parse() {
+    let path: StorePath = "/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo-1.0"
+        .parse()
+        .unwrap();
+    assert_eq!(path.hash_part(), "g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q");
+    assert_eq!(path.name(), "foo-1.0");
+    assert_eq!(path.base_name(), "g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo-1.0");
+    assert_eq!(
+        path.to_string(),
+        "/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo-1.0"
+    );
+    assert_eq!(StorePath::from_base_name(path.base_name()).unwrap(), path);
+    for (path, reason) in [
+        (
+            "/tmp/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo",
+            "not in the store",
+        ),
+        ("/nix/store/g1w7hy3q-foo", "too short"),
+        (
+            "/nix/store/e1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo",
+            "hash part is not nix base32",
+        ),
+        (
+            "/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q_foo",
+            "no '-' after the hash part",
+        ),
+        (
+            "/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo/bar",
+            "name contains a forbidden character",
+        ),
+        (
+            "/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-..",
+            "name is reserved",
+        ),
+    ] {
+        match path.parse::<StorePath>() {
+            Err(Error::InvalidStorePath { reason: r, .. }) => assert_eq!(r, reason, "{}", path),
+            res => panic!("{}: {:?}", path, res),
+        }
+    }
+    let long = format!("g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-{}", "a".repeat(212));
+    assert!(StorePath::from_base_name(&long).is_err());
+    assert!(StorePath::from_base_name(&long[..long.len() - 1]).is_ok());
+
+    let mut buf = vec![];
+    crate::ser::Serializer::new(&mut buf)
+        .encode("/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo/../bar")
+        .unwrap();
+    let mut read: &[u8] = &buf;
+    assert!(matches!(
+        crate::de::Deserializer::new(&mut read).decode::<StorePath>(),
+        Err(Error::InvalidStorePath { .. })
+    ));
+}
+
+#[test]
+fn test_make() {
+    // pkgs.hello.src
+    let hash: Hash = "sha256-jZkUKv2SV28wsM18tCqNxoCZmLxdYH2Idh9RLibH2yA="
+        .parse()
+        .unwrap();
+    assert_eq!(
+        StorePath::make_fixed_output(
+            "hello-2.12.1.tar.gz",
+            FileIngestionMethod::Flat,
+            &hash,
+            &[],
+            false
+        )
+        .unwrap()
+        .to_string(),
+        "/nix/store/pa10z4ngm0g83kx9mssrqzz30s84vq7k-hello-2.12.1.tar.gz"
+    );
+    assert_eq!(
+        StorePath::make_fixed_output(
+            "hello-2.12.1.tar.gz",
+            FileIngestionMethod::Recursive,
+            &hash,
+            &[],
+            false
+        )
+        .unwrap()
+        .to_string(),
+        "/nix/store/5yyljxwfkck991s0vp592v2afcyhya9k-hello-2.12.1.tar.gz"
+    );
+    let sha1 = Hash::from_digest(HashAlgo::Sha1, &hash.digest()[..20]).unwrap();
+    assert_eq!(
+        StorePath::make_fixed_output("foo", FileIngestionMethod::Recursive, &sha1, &[], false)
+            .unwrap()
+            .to_string(),
+        "/nix/store/zv6rpp74irfjy3h43lp56jk63riv1157-foo"
+    );
+    let glibc: StorePath = "/nix/store/9lkz5r8b5srq6z3x5nzi1k8d5mgr4z16-glibc-2.35"
+        .parse()
+        .unwrap();
+    assert_eq!(
+        StorePath::make_fixed_output(
+            "source",
+            FileIngestionMethod::Recursive,
+            &hash,
+            std::slice::from_ref(&glibc),
+            true
+        )
+        .unwrap()
+        .to_string(),
+        "/nix/store/7ajn51165v07p6h1ryghs7aa9zx49aj5-source"
+    );
+    assert!(StorePath::make_fixed_output(
+        "source",
+        FileIngestionMethod::Flat,
+        &hash,
+        std::slice::from_ref(&glibc),
+        false
+    )
+    .is_err());
+
+    // builtins.toFile "hello" "hello world"
+    assert_eq!(
+        StorePath::make_text("hello", &Hash::sha256(b"hello world"), &[])
+            .unwrap()
+            .to_string(),
+        "/nix/store/ivlnvab4q9c7wbsvbfsvgaa15j9p6206-hello"
+    );
+    assert_eq!(
+        StorePath::make_text("builder.sh", &Hash::sha256(b"echo hi"), &[glibc])
+            .unwrap()
+            .to_string(),
+        "/nix/store/5z0nh36ys00z5z3j9mk0c9rikdkiy22d-builder.sh"
+    );
+    assert_eq!(
+        StorePath::make_output("dev", &hash, "hello-2.12.1")
+            .unwrap()
+            .to_string(),
+        "/nix/store/22r9xw73hqxdaxhh38adak7pz72dlpnb-hello-2.12.1-dev"
+    );
+}
@@ -1,29 +1,27 @@
+use crate::consts::BuildStatus;
+use crate::de::WorkerDecode;
+use crate::error::{Error, Result};
+use crate::hash::HashFormat;
+use crate::protocol::protocol_version_minor;
+use crate::ser::WorkerEncode;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
 pub type DrvOutputs = std::collections::HashMap<DrvOutput, Realisation>;
 
-#[derive(
-    Deserialize, Serialize, Clone, Debug, std::cmp::Eq, std::cmp::PartialEq, std::hash::Hash,
-)]
-pub struct Hash {
-    pub hash_size: u64,
-    pub hash: Vec<u8>, // should be of length 64
-}
-
-#[derive(Deserialize, Serialize, Clone, Debug)]
-pub struct StorePath {
-    pub base_name: String,
-}
+pub use crate::content_address::ContentAddress;
+pub use crate::derived_path::{BuiltPath, DerivedPath, OutputsSpec};
+pub use crate::hash::Hash;
+pub use crate::narinfo::NarInfo;
+pub use crate::store_path::StorePath;
 
-#[derive(
-    Deserialize, Serialize, Clone, Debug, std::cmp::Eq, std::cmp::PartialEq, std::hash::Hash,
-)]
+#[derive(Clone, Debug, std::cmp::Eq, std::cmp::PartialEq, std::hash::Hash)]
 pub struct DrvOutput {
     pub drv_hash: Hash,
     pub output_name: String,
 }
 
-#[derive(Deserialize, Serialize, Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Realisation {
     pub id: DrvOutput,
     pub out_path: StorePath,
@@ -31,39 +29,321 @@ pub struct Realisation {
     pub dependent_realisations: std::collections::HashMap<DrvOutput, StorePath>,
 }
 
-#[derive(Deserialize, Serialize, Clone, Debug)]
+/// `sha256:<base16>!<output>`
+impl std::fmt::Display for DrvOutput {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{}!{}",
+            self.drv_hash.to_prefixed(HashFormat::Base16),
+            self.output_name
+        )
+    }
+}
+
+impl std::str::FromStr for DrvOutput {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let (hash, output_name) = s
+            .split_once('!')
+            .ok_or_else(|| Error::Message(format!("invalid drv output {:?}", s)))?;
+        Ok(DrvOutput {
+            drv_hash: hash.parse()?,
+            output_name: output_name.to_string(),
+        })
+    }
+}
+
+impl WorkerEncode for DrvOutput {
+    fn encode<W: std::io::Write>(&self, ser: &mut crate::ser::Serializer<W>) -> Result<()> {
+        self.to_string().encode(ser)
+    }
+}
+
+impl WorkerDecode for DrvOutput {
+    fn decode<R: std::io::Read>(de: &mut crate::de::Deserializer<R>) -> Result<Self> {
+        de.decode::<String>()?.parse()
+    }
+}
+
+crate::de::decode_async_as_string!(DrvOutput);
+
+/// upstream's JSON for a realisation, fields in the order nlohmann prints
+/// them and store paths without the store dir
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RealisationJson {
+    dependent_realisations: BTreeMap<String, String>,
+    id: String,
+    out_path: String,
+    signatures: Vec<String>,
+}
+
+/// sent as JSON
+impl WorkerEncode for Realisation {
+    fn encode<W: std::io::Write>(&self, ser: &mut crate::ser::Serializer<W>) -> Result<()> {
+        let json = RealisationJson {
+            dependent_realisations: self
+                .dependent_realisations
+                .iter()
+                .map(|(id, path)| (id.to_string(), path.base_name().to_string()))
+                .collect(),
+            id: self.id.to_string(),
+            out_path: self.out_path.base_name().to_string(),
+            signatures: self.signature.clone(),
+        };
+        serde_json::to_string(&json)
+            .map_err(|e| Error::Message(e.to_string()))?
+            .encode(ser)
+    }
+}
+
+impl WorkerDecode for Realisation {
+    fn decode<R: std::io::Read>(de: &mut crate::de::Deserializer<R>) -> Result<Self> {
+        let json: RealisationJson = serde_json::from_str(&de.decode::<String>()?)
+            .map_err(|e| Error::Message(e.to_string()))?;
+        Ok(Realisation {
+            id: json.id.parse()?,
+            out_path: StorePath::from_base_name(&json.out_path)?,
+            signature: json.signatures,
+            dependent_realisations: json
+                .dependent_realisations
+                .iter()
+                .map(|(id, path)| Ok((id.parse()?, StorePath::from_base_name(path)?)))
+                .collect::<Result<_>>()?,
+        })
+    }
+}
+
+crate::de::decode_async_as_string!(Realisation);
+
+/// the outcome of building a derivation
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BuildResult {
+    pub status: BuildStatus,
+    pub error_msg: String,
+    /// since 1.29
+    pub times_built: u64,
+    /// since 1.29
+    pub is_non_deterministic: bool,
+    /// unix time in seconds, since 1.29
+    pub start_time: u64,
+    /// since 1.29
+    pub stop_time: u64,
+    /// microseconds, since 1.37
+    pub cpu_user: Option<u64>,
+    /// since 1.37
+    pub cpu_system: Option<u64>,
+    /// realisations by output name, since 1.28
+    pub built_outputs: BTreeMap<String, Realisation>,
+}
+
+impl BuildResult {
+    /// a result without any outputs
+    pub fn new(status: BuildStatus, error_msg: String) -> Self {
+        BuildResult {
+            status,
+            error_msg,
+            times_built: 0,
+            is_non_deterministic: false,
+            start_time: 0,
+            stop_time: 0,
+            cpu_user: None,
+            cpu_system: None,
+            built_outputs: BTreeMap::new(),
+        }
+    }
+}
+
+/// the built outputs are sent keyed by their realisation id
+impl WorkerEncode for BuildResult {
+    fn encode<W: std::io::Write>(&self, ser: &mut crate::ser::Serializer<W>) -> Result<()> {
+        let minor = protocol_version_minor(ser.version());
+        crate::ser::encode_serde(&self.status, ser)?;
+        self.error_msg.encode(ser)?;
+        if minor >= 29 {
+            self.times_built.encode(ser)?;
+            self.is_non_deterministic.encode(ser)?;
+            self.start_time.encode(ser)?;
+            self.stop_time.encode(ser)?;
+        }
+        if minor >= 37 {
+            self.cpu_user.encode(ser)?;
+            self.cpu_system.encode(ser)?;
+        }
+        if minor >= 28 {
+            self.built_outputs
+                .values()
+                .map(|x| (&x.id, x))
+                .collect::<Vec<_>>()
+                .encode(ser)?;
+        }
+        Ok(())
+    }
+}
+
+impl WorkerDecode for BuildResult {
+    fn decode<R: std::io::Read>(de: &mut crate::de::Deserializer<R>) -> Result<Self> {
+        crate::de::decode_struct(de, "BuildResult", |de| {
+            let minor = protocol_version_minor(de.version());
+            let status = crate::de::decode_serde(de).map_err(|e| de.context(e, ".status"))?;
+            let mut res = BuildResult::new(status, de.decode_field("error_msg")?);
+            if minor >= 29 {
+                res.times_built = de.decode_field("times_built")?;
+                res.is_non_deterministic = de.decode_field("is_non_deterministic")?;
+                res.start_time = de.decode_field("start_time")?;
+                res.stop_time = de.decode_field("stop_time")?;
+            }
+            if minor >= 37 {
+                res.cpu_user = de.decode_field("cpu_user")?;
+                res.cpu_system = de.decode_field("cpu_system")?;
+            }
+            if minor >= 28 {
+                let built_outputs: Vec<(DrvOutput, Realisation)> =
+                    de.decode_field("built_outputs")?;
+                for (id, realisation) in built_outputs {
+                    res.built_outputs.insert(id.output_name, realisation);
+                }
+            }
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(feature = "async")]
+impl crate::de::AsyncWorkerDecode for BuildResult {
+    async fn decode_async<R: tokio::io::AsyncRead + Unpin + Send>(
+        de: &mut crate::de::AsyncDeserializer<'_, R>,
+    ) -> Result<Self> {
+        de.enter_struct();
+        let res = async {
+            let minor = protocol_version_minor(de.version());
+            let status = match de.decode_serde_word().await {
+                Ok(status) => status,
+                Err(e) => return Err(de.context(e, ".status")),
+            };
+            let mut res = BuildResult::new(status, de.decode_field("error_msg").await?);
+            if minor >= 29 {
+                res.times_built = de.decode_field("times_built").await?;
+                res.is_non_deterministic = de.decode_field("is_non_deterministic").await?;
+                res.start_time = de.decode_field("start_time").await?;
+                res.stop_time = de.decode_field("stop_time").await?;
+            }
+            if minor >= 37 {
+                res.cpu_user = de.decode_field("cpu_user").await?;
+                res.cpu_system = de.decode_field("cpu_system").await?;
+            }
+            if minor >= 28 {
+                let built_outputs: Vec<(DrvOutput, Realisation)> =
+                    de.decode_field("built_outputs").await?;
+                for (id, realisation) in built_outputs {
+                    res.built_outputs.insert(id.output_name, realisation);
+                }
+            }
+            Ok(res)
+        }
+        .await;
+        de.leave_struct("BuildResult", res)
+    }
+}
+
+/// what the store knows about a valid path, on the wire where the path is
+/// not implied by the request, like `AddToStoreNar`
+#[derive(WorkerEncode, WorkerDecode, Clone, Debug, PartialEq, Eq)]
 pub struct ValidPathInfo {
-    pub path: String,
-    pub deriver: Option<String>,
-    pub hash: String,
-    pub references: Vec<String>,
-    pub registration_time: u64,
-    pub nar_size: u64,
-    pub id: u64,
-    pub ultimate: bool,
-    pub sigs: Vec<String>,
-    pub ca: Option<String>,
+    pub path: StorePath,
+    pub info: UnkeyedValidPathInfo,
 }
 
-#[derive(Deserialize, Serialize, Clone, Debug)]
-pub struct PathInfo {
-    pub path: String,
-    pub info: PathInfoWithoutPath,
+impl ValidPathInfo {
+    /// what signatures of this path are made over
+    pub fn fingerprint(&self) -> Result<String> {
+        crate::signing::fingerprint(
+            &self.path,
+            &self.info.hash,
+            self.info.nar_size,
+            &self.info.references,
+        )
+    }
+    /// add a signature by `key`
+    pub fn sign(&mut self, key: &crate::signing::SecretKey) -> Result<()> {
+        let sig = key.sign(&self.fingerprint()?);
+        if !self.info.sigs.contains(&sig) {
+            self.info.sigs.push(sig);
+        }
+        Ok(())
+    }
+    /// the number of valid signatures by one of `keys`
+    pub fn check_signatures(&self, keys: &[crate::signing::PublicKey]) -> Result<usize> {
+        Ok(crate::signing::count_valid(
+            keys,
+            &self.fingerprint()?,
+            &self.info.sigs,
+        ))
+    }
 }
 
-#[derive(Deserialize, Serialize, Clone, Debug)]
-pub struct PathInfoWithoutPath {
-    pub deriver: String,
-    pub hash: String,
-    pub references: Vec<String>,
+/// upstream's JSON for a path info, keys sorted as nlohmann prints them
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PathInfoJson {
+    ca: Option<String>,
+    deriver: Option<String>,
+    nar_hash: String,
+    nar_size: u64,
+    references: Vec<String>,
+    registration_time: Option<u64>,
+    signatures: Vec<String>,
+    ultimate: bool,
+}
+
+/// `nix path-info --json`, an object keyed by store path
+pub fn path_infos_to_json<'a>(
+    infos: impl IntoIterator<Item = &'a ValidPathInfo>,
+) -> serde_json::Value {
+    serde_json::Value::Object(
+        infos
+            .into_iter()
+            .map(|x| (x.path.to_string(), x.info.to_json()))
+            .collect(),
+    )
+}
+
+/// a path info without its path, on the wire where the request names the
+/// path, like the reply to `QueryPathInfo`
+#[derive(WorkerEncode, WorkerDecode, Clone, Debug, PartialEq, Eq)]
+pub struct UnkeyedValidPathInfo {
+    #[worker(empty_as_none)]
+    pub deriver: Option<StorePath>,
+    pub hash: Hash,
+    pub references: Vec<StorePath>,
     pub registration_time: u64,
     pub nar_size: u64,
     pub ultimate: bool,
     pub sigs: Vec<String>,
-    pub ca: String,
+    #[worker(empty_as_none)]
+    pub ca: Option<ContentAddress>,
+}
+
+impl UnkeyedValidPathInfo {
+    /// the value `nix path-info --json` gives for the path, with the nar
+    /// hash in SRI form and an unknown registration time as null
+    pub fn to_json(&self) -> serde_json::Value {
+        let json = PathInfoJson {
+            ca: self.ca.as_ref().map(|x| x.to_string()),
+            deriver: self.deriver.as_ref().map(|x| x.to_string()),
+            nar_hash: self.hash.to_sri(),
+            nar_size: self.nar_size,
+            references: self.references.iter().map(|x| x.to_string()).collect(),
+            registration_time: Some(self.registration_time).filter(|x| *x != 0),
+            signatures: self.sigs.clone(),
+            ultimate: self.ultimate,
+        };
+        serde_json::to_value(json).unwrap()
+    }
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq, Eq)]
 pub struct DerivationOutput {
     pub name: String,
     pub path_s: String,
@@ -71,18 +351,18 @@ pub struct DerivationOutput {
     pub hash: String,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq, Eq)]
 pub struct BasicDerivation {
     pub name: String, // TODO: parse name from path
     pub outputs: Vec<DerivationOutput>,
-    pub input_srcs: Vec<String>,
+    pub input_srcs: Vec<StorePath>,
     pub platform: String,
     pub builder: String,
     pub args: Vec<String>,
     pub env: Vec<(String, String)>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq)]
 pub struct ClientSettings {
     pub keep_failed: bool,
     pub keep_going: bool,
@@ -98,3 +378,875 @@ pub struct ClientSettings {
     pub use_subsitutes: bool,
     pub overrides: Vec<(String, String)>,
 }
+
+#[cfg(test)]
+use proptest::collection::{hash_map, vec};
+#[cfg(test)]
+use proptest::prelude::*;
+
+#[cfg(test)]
+pub(crate) fn arb_store_path() -> impl Strategy<Value = StorePath> {
+    "[0-9a-df-np-sv-z]{32}-[0-9A-Za-z+_?=-][0-9A-Za-z+._?=-]{0,15}"
+        .prop_map(|base_name| StorePath::from_base_name(&base_name).unwrap())
+}
+
+#[cfg(test)]
+fn arb_drv_output() -> impl Strategy<Value = DrvOutput> {
+    (arb_hash(), any::<String>()).prop_map(|(drv_hash, output_name)| DrvOutput {
+        drv_hash,
+        output_name,
+    })
+}
+
+#[cfg(test)]
+fn arb_hash() -> impl Strategy<Value = Hash> {
+    use crate::hash::HashAlgo;
+    prop_oneof![
+        Just(HashAlgo::Md5),
+        Just(HashAlgo::Sha1),
+        Just(HashAlgo::Sha256),
+        Just(HashAlgo::Sha512),
+    ]
+    .prop_flat_map(|algo| {
+        vec(any::<u8>(), algo.size()).prop_map(move |x| Hash::from_digest(algo, &x).unwrap())
+    })
+}
+
+#[cfg(test)]
+fn arb_content_address() -> impl Strategy<Value = ContentAddress> {
+    use crate::store_path::FileIngestionMethod;
+    prop_oneof![
+        arb_nar_hash().prop_map(ContentAddress::Text),
+        (
+            prop_oneof![
+                Just(FileIngestionMethod::Flat),
+                Just(FileIngestionMethod::Recursive)
+            ],
+            arb_hash()
+        )
+            .prop_map(|(method, hash)| ContentAddress::Fixed { method, hash }),
+    ]
+}
+
+/// nar hashes are sent without the algorithm, so only sha256 ones can be
+/// sent at all, see `test_nar_hash`
+#[cfg(test)]
+fn arb_nar_hash() -> impl Strategy<Value = Hash> {
+    any::<[u8; 32]>().prop_map(|x| Hash::from_digest(crate::hash::HashAlgo::Sha256, &x).unwrap())
+}
+
+#[cfg(test)]
+fn arb_realisation() -> impl Strategy<Value = Realisation> {
+    (
+        arb_drv_output(),
+        arb_store_path(),
+        vec(any::<String>(), 0..4),
+        hash_map(arb_drv_output(), arb_store_path(), 0..4),
+    )
+        .prop_map(
+            |(id, out_path, signature, dependent_realisations)| Realisation {
+                id,
+                out_path,
+                signature,
+                dependent_realisations,
+            },
+        )
+}
+
+#[cfg(test)]
+fn arb_unkeyed_valid_path_info() -> impl Strategy<Value = UnkeyedValidPathInfo> {
+    // empty_as_none fields can not carry Some("")
+    (
+        proptest::option::of(arb_store_path()),
+        arb_nar_hash(),
+        vec(arb_store_path(), 0..4),
+        any::<u64>(),
+        any::<u64>(),
+        any::<bool>(),
+        vec(any::<String>(), 0..4),
+        proptest::option::of(arb_content_address()),
+    )
+        .prop_map(
+            |(deriver, hash, references, registration_time, nar_size, ultimate, sigs, ca)| {
+                UnkeyedValidPathInfo {
+                    deriver,
+                    hash,
+                    references,
+                    registration_time,
+                    nar_size,
+                    ultimate,
+                    sigs,
+                    ca,
+                }
+            },
+        )
+}
+
+#[cfg(test)]
+fn arb_basic_derivation() -> impl Strategy<Value = BasicDerivation> {
+    let output =
+        any::<[String; 4]>().prop_map(|[name, path_s, hash_algo, hash]| DerivationOutput {
+            name,
+            path_s,
+            hash_algo,
+            hash,
+        });
+    (
+        any::<String>(),
+        vec(output, 0..4),
+        vec(arb_store_path(), 0..4),
+        any::<[String; 2]>(),
+        vec(any::<String>(), 0..4),
+        vec(any::<(String, String)>(), 0..4),
+    )
+        .prop_map(
+            |(name, outputs, input_srcs, [platform, builder], args, env)| BasicDerivation {
+                name,
+                outputs,
+                input_srcs,
+                platform,
+                builder,
+                args,
+                env,
+            },
+        )
+}
+
+#[cfg(test)]
+fn arb_client_settings() -> impl Strategy<Value = ClientSettings> {
+    (
+        any::<[bool; 4]>(),
+        any::<[[u64; 4]; 2]>(),
+        vec(any::<(String, String)>(), 0..4),
+    )
+        .prop_map(|(flags, nums, overrides)| {
+            let [keep_failed, keep_going, try_fallback, use_subsitutes] = flags;
+            let [verbosity, max_build_jobs, max_silent_time, use_build_hook] = nums[0];
+            let [verbose_build, log_type, print_build_trace, build_cores] = nums[1];
+            ClientSettings {
+                keep_failed,
+                keep_going,
+                try_fallback,
+                verbosity,
+                max_build_jobs,
+                max_silent_time,
+                use_build_hook,
+                verbose_build,
+                log_type,
+                print_build_trace,
+                build_cores,
+                use_subsitutes,
+                overrides,
+            }
+        })
+}
+
+#[cfg(test)]
+fn serde_round_trip<T>(value: &T, version: u64) -> std::result::Result<(), TestCaseError>
+where
+    T: Serialize + serde::de::DeserializeOwned + PartialEq + std::fmt::Debug,
+{
+    let mut buf = vec![];
+    value.serialize(&mut crate::ser::Serializer::with_version(&mut buf, version))?;
+    let mut read: &[u8] = &buf;
+    let decoded = T::deserialize(&mut crate::de::Deserializer::with_version(
+        &mut read, version,
+    ))?;
+    prop_assert_eq!(&decoded, value);
+    prop_assert!(read.is_empty());
+    Ok(())
+}
+
+#[cfg(test)]
+fn worker_round_trip<T>(value: &T, version: u64) -> std::result::Result<(), TestCaseError>
+where
+    T: WorkerEncode + WorkerDecode + PartialEq + std::fmt::Debug,
+{
+    let mut buf = vec![];
+    crate::ser::Serializer::with_version(&mut buf, version).encode(value)?;
+    let mut read: &[u8] = &buf;
+    let decoded: T = crate::de::Deserializer::with_version(&mut read, version).decode()?;
+    prop_assert_eq!(&decoded, value);
+    prop_assert!(read.is_empty());
+    Ok(())
+}
+
+#[cfg(test)]
+fn arb_version() -> impl Strategy<Value = u64> {
+    use crate::protocol::*;
+    (protocol_version_minor(MIN_PROTOCOL_VERSION)..=protocol_version_minor(PROTOCOL_VERSION))
+        .prop_map(|minor| protocol_version_major(PROTOCOL_VERSION) | minor)
+}
+
+#[cfg(test)]
+proptest! {
+    #[test]
+    fn test_valid_path_info(
+        path in arb_store_path(),
+        info in arb_unkeyed_valid_path_info(),
+        version in arb_version(),
+    ) {
+        worker_round_trip(&ValidPathInfo { path, info: info.clone() }, version)?;
+        worker_round_trip(&Some(info), version)?;
+    }
+
+    #[test]
+    fn test_nar_hash(
+        hash in arb_hash(),
+        info in arb_unkeyed_valid_path_info(),
+        version in arb_version(),
+    ) {
+        let sha256 = hash.algo() == crate::hash::HashAlgo::Sha256;
+        let info = UnkeyedValidPathInfo { hash, ..info };
+        let mut buf = vec![];
+        let res = crate::ser::Serializer::with_version(&mut buf, version).encode(&info);
+        prop_assert_eq!(res.is_ok(), sha256);
+        if sha256 {
+            worker_round_trip(&info, version)?;
+        }
+    }
+
+    #[test]
+    fn test_store_path(value in arb_store_path(), version in arb_version()) {
+        serde_round_trip(&value, version)?;
+        worker_round_trip(&value, version)?;
+    }
+
+    #[test]
+    fn test_realisation(
+        value in hash_map(arb_drv_output(), arb_realisation(), 0..4),
+        version in arb_version(),
+    ) {
+        worker_round_trip(&value, version)?;
+    }
+
+    #[test]
+    fn test_realisation_json(value in arb_realisation(), version in arb_version()) {
+        worker_round_trip(&value.id, version)?;
+        worker_round_trip(&value, version)?;
+    }
+
+    #[test]
+    fn test_basic_derivation(value in arb_basic_derivation(), version in arb_version()) {
+        serde_round_trip(&value, version)?;
+    }
+
+    #[test]
+    fn test_client_settings(value in arb_client_settings(), version in arb_version()) {
+        serde_round_trip(&value, version)?;
+    }
+}
+
+#[test]
+fn test_sign_path_info() {
+    let key: crate::signing::SecretKey = "test-1:nWGxne/9WmC6hEr0kuwsxERJxWl7MmkZcDusAxyuf2DXWpgBgrEKt9VL/tPJZAc6DuFy89qmIyWvAhpo9wdRGg=="
+        .parse()
+        .unwrap();
+    let [info, _] = fixture_path_infos();
+    let mut path_info = ValidPathInfo {
+        path: "/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-bar"
+            .parse()
+            .unwrap(),
+        info,
+    };
+    assert_eq!(
+        path_info.check_signatures(&[key.to_public_key()]).unwrap(),
+        0
+    );
+    path_info.sign(&key).unwrap();
+    path_info.sign(&key).unwrap();
+    assert_eq!(path_info.info.sigs.len(), 1);
+    assert_eq!(
+        path_info.check_signatures(&[key.to_public_key()]).unwrap(),
+        1
+    );
+    path_info.info.nar_size += 1;
+    assert_eq!(
+        path_info.check_signatures(&[key.to_public_key()]).unwrap(),
+        0
+    );
+}
+
+#[test]
+fn test_path_info_errors() {
+    let [_, info] = fixture_path_infos();
+    let info = ValidPathInfo {
+        path: "/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo"
+            .parse()
+            .unwrap(),
+        info,
+    };
+    let mut buf = vec![];
+    crate::ser::Serializer::new(&mut buf).encode(&info).unwrap();
+    // `e` is not a nix32 digit
+    let reference = b"/nix/store/g1w7hyyyy1w7hyyyy1w7hyyyy1w7hyyy-bar";
+    let pos = buf
+        .windows(reference.len())
+        .position(|x| x == reference)
+        .unwrap();
+    buf[pos + 11] = b'e';
+    let err = crate::de::Deserializer::from_slice(&buf)
+        .decode::<ValidPathInfo>()
+        .unwrap_err();
+    assert!(matches!(err.root(), Error::InvalidStorePath { .. }));
+    assert_eq!(
+        err.to_string(),
+        format!(
+            "ValidPathInfo.info.references[1]: {} at offset {}",
+            err.root(),
+            pos - 8
+        )
+    );
+}
+
+#[test]
+fn test_path_info_json() {
+    let [a, b] = fixture_path_infos();
+    let infos = [
+        ValidPathInfo {
+            path: "/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-bar"
+                .parse()
+                .unwrap(),
+            info: UnkeyedValidPathInfo {
+                registration_time: 0,
+                ..a
+            },
+        },
+        ValidPathInfo {
+            path: "/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo"
+                .parse()
+                .unwrap(),
+            info: b,
+        },
+    ];
+    assert_eq!(
+        path_infos_to_json(&infos).to_string(),
+        r#"{"/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-bar":{"ca":null,"deriver":null,"#
+            .to_owned()
+            + r#""narHash":"sha256-FePFYIlMuycIXPZbWi7LGEiMmZSX9FMbaQenWBzm1Sc=","narSize":34878,"#
+            + r#""references":[],"registrationTime":null,"signatures":[],"ultimate":true},"#
+            + r#""/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo":{"#
+            + r#""ca":"fixed:r:sha256:1lr187v6dck1rjh2j6svpikcfz53wyl3qrlcbb405zlh13x0khhh","#
+            + r#""deriver":"/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-bar.drv","#
+            + r#""narHash":"sha256-FePFYIlMuycIXPZbWi7LGEiMmZSX9FMbaQenWBzm1Sc=","narSize":34878,"#
+            + r#""references":["/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo","#
+            + r#""/nix/store/g1w7hyyyy1w7hyyyy1w7hyyyy1w7hyyy-bar"],"registrationTime":23423,"#
+            + r#""signatures":["fake-sig-1","fake-sig-2"],"ultimate":false}}"#
+    );
+}
+
+#[test]
+fn test_tags_round_trip() {
+    use crate::consts::BuildStatus;
+    use crate::protocol::Op;
+    fn round_trip<T: Serialize + serde::de::DeserializeOwned>() -> usize {
+        (0..256_u64)
+            .filter(|tag| {
+                let mut read: &[u8] = &tag.to_le_bytes();
+                match T::deserialize(&mut crate::de::Deserializer::new(&mut read)) {
+                    Ok(value) => {
+                        let mut buf = vec![];
+                        value
+                            .serialize(&mut crate::ser::Serializer::new(&mut buf))
+                            .unwrap();
+                        assert_eq!(buf, tag.to_le_bytes());
+                        true
+                    }
+                    Err(_) => false,
+                }
+            })
+            .count()
+    }
+    assert_eq!(round_trip::<Op>(), 34);
+    assert_eq!(round_trip::<BuildStatus>(), 13);
+}
+
+/// a golden encoding under `fixtures/`, see the READMEs there
+#[cfg(test)]
+#[derive(Clone, Copy)]
+enum Fixture {
+    /// `worker-protocol/{name}.bin`, upstream's file of that name, at
+    /// protocol version 1.{minor}
+    Upstream(&'static str, u64),
+    /// `sirius/{name}.bin`, written by our own encoder at the latest version
+    /// and rewritten by running the tests with `_SIRIUS_TEST_ACCEPT=1`
+    Sirius(&'static str),
+}
+
+/// decode `fixture` into `values`, written back to back, and check that
+/// encoding them again gives back the same bytes
+#[cfg(test)]
+fn check_fixture<T: PartialEq + std::fmt::Debug>(
+    fixture: Fixture,
+    values: &[T],
+    encode: impl Fn(&T, &mut crate::ser::Serializer<Vec<u8>>) -> crate::error::Result<()>,
+    decode: impl Fn(&mut crate::de::Deserializer<&[u8]>) -> crate::error::Result<T>,
+) {
+    let major = crate::protocol::protocol_version_major(crate::protocol::PROTOCOL_VERSION);
+    let (path, version) = match fixture {
+        Fixture::Upstream(name, minor) => (format!("worker-protocol/{}.bin", name), major | minor),
+        Fixture::Sirius(name) => (
+            format!("sirius/{}.bin", name),
+            crate::protocol::PROTOCOL_VERSION,
+        ),
+    };
+    let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("fixtures")
+        .join(path);
+    if let Fixture::Sirius(_) = fixture {
+        if std::env::var_os("_SIRIUS_TEST_ACCEPT").is_some() {
+            let mut buf = vec![];
+            let mut ser = crate::ser::Serializer::with_version(&mut buf, version);
+            values.iter().for_each(|x| encode(x, &mut ser).unwrap());
+            std::fs::write(&path, &buf).unwrap();
+            return;
+        }
+    }
+    let fixture = std::fs::read(&path).unwrap();
+    let mut read: &[u8] = &fixture;
+    let mut des = crate::de::Deserializer::with_version(&mut read, version);
+    let decoded: Vec<T> = values.iter().map(|_| decode(&mut des).unwrap()).collect();
+    assert_eq!(decoded, values, "decoding {}", path.display());
+    assert!(read.is_empty(), "trailing bytes in {}", path.display());
+
+    let mut encoded = vec![];
+    let mut ser = crate::ser::Serializer::with_version(&mut encoded, version);
+    decoded.iter().for_each(|x| encode(x, &mut ser).unwrap());
+    assert_eq!(
+        encoded,
+        fixture,
+        "encoding {}, see fixtures/README.md",
+        path.display()
+    );
+}
+
+#[cfg(test)]
+fn serde_fixture<T: Serialize + serde::de::DeserializeOwned + PartialEq + std::fmt::Debug>(
+    fixture: Fixture,
+    values: &[T],
+) {
+    check_fixture(
+        fixture,
+        values,
+        |x, ser| x.serialize(ser),
+        |des| T::deserialize(des),
+    );
+}
+
+#[cfg(test)]
+fn worker_fixture<T: WorkerEncode + WorkerDecode + PartialEq + std::fmt::Debug>(
+    fixture: Fixture,
+    values: &[T],
+) {
+    check_fixture(fixture, values, |x, ser| ser.encode(x), |des| des.decode());
+}
+
+#[cfg(test)]
+fn fixture_path_infos() -> [UnkeyedValidPathInfo; 2] {
+    [
+        UnkeyedValidPathInfo {
+            deriver: None,
+            hash: "sha256-FePFYIlMuycIXPZbWi7LGEiMmZSX9FMbaQenWBzm1Sc="
+                .parse()
+                .unwrap(),
+            references: vec![],
+            registration_time: 23423,
+            nar_size: 34878,
+            ultimate: true,
+            sigs: vec![],
+            ca: None,
+        },
+        UnkeyedValidPathInfo {
+            deriver: Some(
+                "/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-bar.drv"
+                    .parse()
+                    .unwrap(),
+            ),
+            hash: "sha256-FePFYIlMuycIXPZbWi7LGEiMmZSX9FMbaQenWBzm1Sc="
+                .parse()
+                .unwrap(),
+            references: vec![
+                "/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo"
+                    .parse()
+                    .unwrap(),
+                "/nix/store/g1w7hyyyy1w7hyyyy1w7hyyyy1w7hyyy-bar"
+                    .parse()
+                    .unwrap(),
+            ],
+            registration_time: 23423,
+            nar_size: 34878,
+            ultimate: false,
+            sigs: vec![String::from("fake-sig-1"), String::from("fake-sig-2")],
+            ca: Some(
+                "fixed:r:sha256:1lr187v6dck1rjh2j6svpikcfz53wyl3qrlcbb405zlh13x0khhh"
+                    .parse()
+                    .unwrap(),
+            ),
+        },
+    ]
+}
+
+#[test]
+fn test_fixture_string() {
+    worker_fixture(
+        Fixture::Upstream("string", 10),
+        &[
+            String::from(""),
+            String::from("hi"),
+            String::from("white rabbit"),
+            String::from("大白兔"),
+            String::from("oh no \0\0\0 what was that!"),
+        ],
+    );
+}
+
+#[test]
+fn test_fixture_vector() {
+    let strings = |x: &[&str]| x.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+    serde_fixture(
+        Fixture::Upstream("vector", 10),
+        &[(
+            strings(&[]),
+            strings(&[""]),
+            strings(&["", "foo", "bar"]),
+            vec![strings(&[]), strings(&[""]), strings(&["", "1", "2"])],
+        )],
+    );
+}
+
+#[test]
+fn test_fixture_store_path() {
+    worker_fixture(
+        Fixture::Upstream("store-path", 10),
+        &[
+            StorePath::from_base_name("g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo").unwrap(),
+            StorePath::from_base_name("g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo-bar").unwrap(),
+        ],
+    );
+}
+
+#[test]
+fn test_fixture_derived_path() {
+    let foo = StorePath::from_base_name("g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo").unwrap();
+    let foo_drv = StorePath::from_base_name("g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo.drv").unwrap();
+    let bar_drv = StorePath::from_base_name("g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-bar.drv").unwrap();
+    let all = DerivedPath::Built {
+        drv_path: bar_drv.clone(),
+        outputs: OutputsSpec::All,
+    };
+    let names = DerivedPath::Built {
+        drv_path: bar_drv,
+        outputs: "x,y".parse().unwrap(),
+    };
+    // before 1.30 a derivation stands for all of its outputs
+    worker_fixture(
+        Fixture::Upstream("derived-path-1.29", 29),
+        &[DerivedPath::Opaque(foo.clone()), all.clone(), names.clone()],
+    );
+    worker_fixture(
+        Fixture::Upstream("derived-path-1.30", 30),
+        &[
+            DerivedPath::Opaque(foo),
+            DerivedPath::Opaque(foo_drv),
+            all,
+            names,
+        ],
+    );
+}
+
+#[test]
+fn test_fixture_path_info() {
+    let [a, b] = fixture_path_infos();
+    worker_fixture(
+        Fixture::Sirius("unkeyed-valid-path-info"),
+        &[a.clone(), b.clone()],
+    );
+    worker_fixture(
+        Fixture::Sirius("optional-unkeyed-valid-path-info"),
+        &[None, Some(b.clone())],
+    );
+    worker_fixture(
+        Fixture::Sirius("valid-path-info"),
+        &[
+            ValidPathInfo {
+                path: "/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-bar"
+                    .parse()
+                    .unwrap(),
+                info: a,
+            },
+            ValidPathInfo {
+                path: "/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo"
+                    .parse()
+                    .unwrap(),
+                info: b,
+            },
+        ],
+    );
+}
+
+#[cfg(test)]
+fn fixture_realisation() -> Realisation {
+    Realisation {
+        id: DrvOutput {
+            drv_hash: "sha256-FePFYIlMuycIXPZbWi7LGEiMmZSX9FMbaQenWBzm1Sc="
+                .parse()
+                .unwrap(),
+            output_name: String::from("baz"),
+        },
+        out_path: "/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo"
+            .parse()
+            .unwrap(),
+        signature: vec![String::from("asdf"), String::from("qwer")],
+        dependent_realisations: Default::default(),
+    }
+}
+
+#[cfg(test)]
+fn fixture_drv_output(output_name: &str) -> DrvOutput {
+    DrvOutput {
+        drv_hash: "sha256-b4afnqKCO9oWXgYHb9DeQ2berSwOjS27rSd9TxXDc/U="
+            .parse()
+            .unwrap(),
+        output_name: output_name.to_string(),
+    }
+}
+
+#[test]
+fn test_fixture_realisation() {
+    let realisation = fixture_realisation();
+    let mut other = realisation.clone();
+    other
+        .dependent_realisations
+        .insert(fixture_drv_output("quux"), realisation.out_path.clone());
+    worker_fixture(
+        Fixture::Upstream("drv-output", 10),
+        &[realisation.id.clone(), fixture_drv_output("quux")],
+    );
+    worker_fixture(Fixture::Upstream("realisation", 10), &[realisation, other]);
+}
+
+#[test]
+fn test_fixture_build_result() {
+    let rejected = BuildResult::new(BuildStatus::OutputRejected, String::from("no idea why"));
+    let not_deterministic =
+        BuildResult::new(BuildStatus::NotDeterministic, String::from("no idea why"));
+    let mut built = BuildResult::new(BuildStatus::Built, String::new());
+    worker_fixture(
+        Fixture::Upstream("build-result-1.27", 27),
+        &[rejected.clone(), not_deterministic.clone(), built.clone()],
+    );
+
+    for name in ["foo", "bar"] {
+        let realisation = Realisation {
+            id: fixture_drv_output(name),
+            out_path: StorePath::from_base_name(&format!(
+                "g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-{}",
+                name
+            ))
+            .unwrap(),
+            signature: vec![],
+            dependent_realisations: Default::default(),
+        };
+        built.built_outputs.insert(name.to_string(), realisation);
+    }
+    worker_fixture(
+        Fixture::Upstream("build-result-1.28", 28),
+        &[rejected.clone(), not_deterministic.clone(), built.clone()],
+    );
+
+    let not_deterministic = BuildResult {
+        times_built: 3,
+        is_non_deterministic: true,
+        start_time: 30,
+        stop_time: 50,
+        ..not_deterministic
+    };
+    let mut built = BuildResult {
+        times_built: 1,
+        start_time: 30,
+        stop_time: 50,
+        ..built
+    };
+    worker_fixture(
+        Fixture::Upstream("build-result-1.29", 29),
+        &[rejected.clone(), not_deterministic.clone(), built.clone()],
+    );
+
+    built.cpu_user = Some(500_000_000);
+    built.cpu_system = Some(604_000_000);
+    worker_fixture(
+        Fixture::Upstream("build-result-1.37", 37),
+        &[rejected, not_deterministic, built],
+    );
+}
+
+#[test]
+fn test_build_result_versions() {
+    use crate::ser::Serializer;
+    let realisation = fixture_realisation();
+    let result = BuildResult {
+        status: BuildStatus::Built,
+        error_msg: String::new(),
+        times_built: 3,
+        is_non_deterministic: true,
+        start_time: 30,
+        stop_time: 50,
+        cpu_user: Some(500_000),
+        cpu_system: None,
+        built_outputs: [(String::from("baz"), realisation.clone())]
+            .into_iter()
+            .collect(),
+    };
+    for minor in [27, 28, 29, 37] {
+        let version = 1 << 8 | minor;
+        let mut buf = vec![];
+        Serializer::with_version(&mut buf, version)
+            .encode(&result)
+            .unwrap();
+
+        // what upstream sends, field by field
+        let mut expected = vec![];
+        let mut ser = Serializer::with_version(&mut expected, version);
+        ser.encode(&(BuildStatus::Built as u64)).unwrap();
+        ser.encode("").unwrap();
+        if minor >= 29 {
+            ser.encode(&3_u64).unwrap();
+            ser.encode(&true).unwrap();
+            ser.encode(&30_u64).unwrap();
+            ser.encode(&50_u64).unwrap();
+        }
+        if minor >= 37 {
+            ser.encode(&Some(500_000_u64)).unwrap();
+            ser.encode(&None::<u64>).unwrap();
+        }
+        if minor >= 28 {
+            ser.encode(&1_u64).unwrap();
+            ser.encode(&realisation.id.to_string()).unwrap();
+            ser.encode(r#"{"dependentRealisations":{},"id":"sha256:15e3c560894cbb27085cf65b5a2ecb18488c999497f4531b6907a7581ce6d527!baz","outPath":"g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo","signatures":["asdf","qwer"]}"#).unwrap();
+        }
+        assert_eq!(buf, expected, "1.{}", minor);
+
+        let mut read: &[u8] = &buf;
+        let decoded: BuildResult = crate::de::Deserializer::with_version(&mut read, version)
+            .decode()
+            .unwrap();
+        assert!(read.is_empty());
+        if minor >= 37 {
+            assert_eq!(decoded, result);
+        }
+        assert_eq!(decoded.built_outputs.is_empty(), minor < 28);
+    }
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn test_build_result_async() {
+    let mut result = BuildResult::new(BuildStatus::Built, String::from("no idea why"));
+    result.times_built = 3;
+    result.cpu_user = Some(500_000);
+    let realisation = fixture_realisation();
+    result
+        .built_outputs
+        .insert(String::from("baz"), realisation);
+    for minor in [27, 28, 29, 37] {
+        let version = 1 << 8 | minor;
+        let mut buf = vec![];
+        crate::ser::Serializer::with_version(&mut buf, version)
+            .encode(&result)
+            .unwrap();
+        let expected: BuildResult = crate::de::Deserializer::with_version(&mut &buf[..], version)
+            .decode()
+            .unwrap();
+        let mut read: &[u8] = &buf;
+        let decoded: BuildResult = crate::de::AsyncDeserializer::with_version(&mut read, version)
+            .decode()
+            .await
+            .unwrap();
+        assert_eq!(decoded, expected, "1.{}", minor);
+        assert!(read.is_empty());
+    }
+    let mut read: &[u8] = &[0x2a, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+    let err = crate::de::AsyncDeserializer::new(&mut read)
+        .decode::<BuildResult>()
+        .await
+        .unwrap_err();
+    assert!(
+        err.to_string().starts_with("BuildResult.status: "),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn test_fixture_basic_derivation() {
+    serde_fixture(
+        Fixture::Sirius("basic-derivation"),
+        &[BasicDerivation {
+            name: String::from("/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-foo.drv"),
+            outputs: vec![DerivationOutput {
+                name: String::from("out"),
+                path_s: String::from("/nix/store/g1w7hyyyy1w7hyyyy1w7hyyyy1w7hyyy-foo"),
+                hash_algo: String::from(""),
+                hash: String::from(""),
+            }],
+            input_srcs: vec!["/nix/store/g1w7hy3qg1w7hy3qg1w7hy3qg1w7hy3q-bar"
+                .parse()
+                .unwrap()],
+            platform: String::from("x86_64-linux"),
+            builder: String::from("/bin/sh"),
+            args: vec![String::from("-c"), String::from("echo foo > $out")],
+            env: vec![
+                (String::from("builder"), String::from("/bin/sh")),
+                (
+                    String::from("out"),
+                    String::from("/nix/store/g1w7hyyyy1w7hyyyy1w7hyyyy1w7hyyy-foo"),
+                ),
+            ],
+        }],
+    );
+}
+
+#[test]
+fn test_fixture_client_settings() {
+    serde_fixture(
+        Fixture::Sirius("client-settings"),
+        &[ClientSettings {
+            keep_failed: false,
+            keep_going: true,
+            try_fallback: false,
+            verbosity: 3,
+            max_build_jobs: 16,
+            max_silent_time: 0,
+            use_build_hook: 1,
+            verbose_build: 1,
+            log_type: 0,
+            print_build_trace: 0,
+            build_cores: 0,
+            use_subsitutes: true,
+            overrides: vec![(String::from("sandbox"), String::from("true"))],
+        }],
+    );
+}
+
+#[test]
+fn test_fixture_tags() {
+    use crate::consts::BuildStatus;
+    use crate::protocol::Op;
+    serde_fixture(
+        Fixture::Sirius("op"),
+        &[
+            Op::Nop,
+            Op::SetOptions,
+            Op::QueryPathInfo,
+            Op::AddMultipleToStore,
+        ],
+    );
+    serde_fixture(
+        Fixture::Sirius("build-status"),
+        &[
+            BuildStatus::Built,
+            BuildStatus::InputRejected,
+            BuildStatus::NotDeterministic,
+        ],
+    );
+}